@@ -0,0 +1,215 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::AcmeError;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// RFC 8555 7.1.3, `certificate` is only there once the order is valid
+#[derive(Debug, Deserialize)]
+pub struct Order {
+    pub status: String,
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    pub certificate: Option<String>,
+}
+
+/// RFC 8555 7.1.4
+#[derive(Debug, Deserialize)]
+pub struct Authorization {
+    pub status: String,
+    pub identifier: Identifier,
+    pub challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Identifier {
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Challenge {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    //only the token based challenge types have one
+    #[serde(default)]
+    pub token: String,
+}
+
+//RFC 7807 problem document, what ACME servers answer errors with
+#[derive(Debug, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+/// An account on an ACME directory, every request to it is a JWS signed with the account key
+pub struct Account {
+    agent: ureq::Agent,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    directory: Directory,
+    //the account URL, sent as `kid` once the account exists
+    url: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// Fetches the directory and finds (or creates) the account for `key`, a PKCS#8 P-256 key
+    pub fn open(agent: ureq::Agent, directory_url: &str, key: &[u8], contact: &[String]) -> Result<Account, AcmeError> {
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, key, &rng)
+            .map_err(|e| AcmeError::Key(e.to_string()))?;
+        let directory = match agent.get(directory_url).call() {
+            Ok(r) => read_json(r)?,
+            Err(e) => return Err(request_error(e)),
+        };
+
+        let mut account = Account { agent, key, rng, directory, url: None, nonce: None };
+        let new_account = account.directory.new_account.clone();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+        let response = account.post(&new_account, Some(&payload))?;
+        account.url = Some(location(&response)?);
+        Ok(account)
+    }
+
+    /// The order for `names` and its URL
+    pub fn new_order(&mut self, names: &[String]) -> Result<(String, Order), AcmeError> {
+        let identifiers = names.iter().map(|n| json!({ "type": "dns", "value": n })).collect::<Vec<_>>();
+        let new_order = self.directory.new_order.clone();
+        let response = self.post(&new_order, Some(&json!({ "identifiers": identifiers })))?;
+        let url = location(&response)?;
+        Ok((url, read_json(response)?))
+    }
+
+    pub fn order(&mut self, url: &str) -> Result<Order, AcmeError> {
+        read_json(self.post(url, None)?)
+    }
+
+    pub fn authorization(&mut self, url: &str) -> Result<Authorization, AcmeError> {
+        read_json(self.post(url, None)?)
+    }
+
+    /// Tells the server the challenge can be checked now
+    pub fn respond(&mut self, challenge_url: &str) -> Result<(), AcmeError> {
+        self.post(challenge_url, Some(&json!({}))).map(|_| ())
+    }
+
+    pub fn finalize(&mut self, url: &str, csr_der: &[u8]) -> Result<Order, AcmeError> {
+        read_json(self.post(url, Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr_der) })))?)
+    }
+
+    /// The PEM chain of an issued certificate, leaf first
+    pub fn certificate(&mut self, url: &str) -> Result<String, AcmeError> {
+        self.post(url, None)?
+            .into_string()
+            .map_err(|e| AcmeError::Http(e.to_string()))
+    }
+
+    /// What the HTTP-01 challenge for `token` has to be answered with (RFC 8555 8.1)
+    pub fn key_authorization(&self, token: &str) -> String {
+        let thumbprint = ring::digest::digest(&ring::digest::SHA256, self.jwk().to_string().as_bytes());
+        format!("{}.{}", token, URL_SAFE_NO_PAD.encode(thumbprint))
+    }
+
+    //RFC 7638 wants the members in lexicographic order, which is how serde_json writes them
+    fn jwk(&self) -> Value {
+        //an uncompressed point, 0x04 followed by x and y
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        })
+    }
+
+    //a None payload is a POST-as-GET (RFC 8555 6.3); a stale nonce is retried once with the fresh
+    //one the error came with
+    fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<ureq::Response, AcmeError> {
+        let mut retried = false;
+        loop {
+            let body = self.sign(url, payload)?;
+            let result = self.agent.post(url)
+                .set("Content-Type", "application/jose+json")
+                .send_bytes(body.as_bytes());
+            let response = match result {
+                Ok(r) => r,
+                Err(ureq::Error::Status(status, r)) => {
+                    self.nonce = r.header("Replay-Nonce").map(str::to_string);
+                    let problem = read_json::<Problem>(r).unwrap_or(Problem { kind: String::new(), detail: String::new() });
+                    if problem.kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                        retried = true;
+                        continue;
+                    }
+                    return Err(AcmeError::Problem { status, kind: problem.kind, detail: problem.detail });
+                },
+                Err(e) => return Err(request_error(e)),
+            };
+            self.nonce = response.header("Replay-Nonce").map(str::to_string);
+            return Ok(response);
+        }
+    }
+
+    //flattened JSON serialization (RFC 7515 7.2.2), ES256 signatures are the raw r and s
+    fn sign(&mut self, url: &str, payload: Option<&Value>) -> Result<String, AcmeError> {
+        let nonce = match self.nonce.take() {
+            Some(n) => n,
+            None => self.fresh_nonce()?,
+        };
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.url {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload.map(|p| URL_SAFE_NO_PAD.encode(p.to_string())).unwrap_or_default();
+        let signature = self.key.sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|e| AcmeError::Key(e.to_string()))?;
+        let jws = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        });
+        Ok(jws.to_string())
+    }
+
+    fn fresh_nonce(&self) -> Result<String, AcmeError> {
+        let response = self.agent.head(&self.directory.new_nonce).call().map_err(request_error)?;
+        response.header("Replay-Nonce")
+            .map(str::to_string)
+            .ok_or_else(|| AcmeError::Protocol("newNonce answered without a Replay-Nonce".to_string()))
+    }
+}
+
+fn location(response: &ureq::Response) -> Result<String, AcmeError> {
+    response.header("Location")
+        .map(str::to_string)
+        .ok_or_else(|| AcmeError::Protocol(format!("no Location in the answer from {}", response.get_url())))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(response: ureq::Response) -> Result<T, AcmeError> {
+    let url = response.get_url().to_string();
+    serde_json::from_reader(response.into_reader()).map_err(|e| AcmeError::Protocol(format!("unexpected answer from {}: {}", url, e)))
+}
+
+fn request_error(e: ureq::Error) -> AcmeError {
+    match e {
+        ureq::Error::Status(status, r) => AcmeError::Problem { status, kind: String::new(), detail: r.status_text().to_string() },
+        ureq::Error::Transport(t) => AcmeError::Http(t.to_string()),
+    }
+}
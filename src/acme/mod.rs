@@ -0,0 +1,341 @@
+mod client;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rcgen::{CertificateParams, KeyPair};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::{ClientConfig, RootCertStore};
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::{AcmeConfig, TlsConfig};
+use crate::tls::{self, TlsError};
+use client::Account;
+
+/// Where HTTP-01 challenges are fetched from (RFC 8555 8.3)
+pub static CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+//how long a certificate that doesn't need renewing yet goes unchecked at most
+static CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+//orders and authorizations are checked this often while the CA works on them, for this many times
+static POLL_INTERVAL: Duration = Duration::from_secs(1);
+static POLL_ATTEMPTS: u32 = 60;
+
+#[derive(Debug)]
+pub enum AcmeError {
+    Io(PathBuf, std::io::Error),
+    Http(String),
+    Problem { status: u16, kind: String, detail: String },
+    Protocol(String),
+    Key(String),
+    Tls(TlsError),
+    //shutdown came while waiting on the CA
+    Stopped,
+}
+
+impl fmt::Display for AcmeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcmeError::Io(p, e) => write!(f, "unable to access {}: {}", p.display(), e),
+            AcmeError::Http(e) => write!(f, "unable to reach the ACME directory: {}", e),
+            AcmeError::Problem { status, kind, detail } => write!(f, "ACME server answered {} {} {}", status, kind, detail),
+            AcmeError::Protocol(e) => write!(f, "ACME exchange failed, {}", e),
+            AcmeError::Key(e) => write!(f, "key error: {}", e),
+            AcmeError::Tls(e) => e.fmt(f),
+            AcmeError::Stopped => write!(f, "stopped before the certificate was issued"),
+        }
+    }
+}
+
+impl std::error::Error for AcmeError {}
+
+impl From<AcmeError> for std::io::Error {
+    fn from(e: AcmeError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+impl From<rcgen::Error> for AcmeError {
+    fn from(e: rcgen::Error) -> AcmeError {
+        AcmeError::Key(e.to_string())
+    }
+}
+
+/// Key authorizations of the HTTP-01 challenges in flight, by token
+#[derive(Debug, Default)]
+pub struct Challenges(Mutex<HashMap<String, String>>);
+
+impl Challenges {
+    /// The key authorization to answer a request for `path` with, when it is one of our challenges
+    pub fn answer(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(CHALLENGE_PATH)?;
+        self.0.lock().unwrap().get(token).cloned()
+    }
+
+    fn insert(&self, token: &str, key_authorization: String) {
+        self.0.lock().unwrap().insert(token.to_string(), key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        self.0.lock().unwrap().remove(token);
+    }
+}
+
+/// Keeps `tls.cert` and `tls.key` issued by an ACME CA, renewing them from a thread of its own.
+/// The TLS watcher picks the new files up like any other renewal.
+pub struct Acme {
+    //dropping the sender wakes the thread up and tells it to stop
+    stop: Mutex<Option<Sender<()>>>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Acme {
+    /// Writes a self-signed placeholder when there is no certificate yet, so the TLS listeners can
+    /// start before the first one is issued. Being self-signed it gets replaced straight away.
+    pub fn prepare(tls: &TlsConfig, acme: &AcmeConfig) -> Result<(), AcmeError> {
+        fs::create_dir_all(&acme.state_dir).map_err(|e| AcmeError::Io(acme.state_dir.clone(), e))?;
+        if tls.cert.is_file() && tls.key.is_file() {
+            return Ok(());
+        }
+
+        log::warn!("no certificate at {} yet, serving a self-signed one until the CA issues it", tls.cert.display());
+        let key = KeyPair::generate()?;
+        let cert = CertificateParams::new(acme.names.clone())?.self_signed(&key)?;
+        write_atomic(&tls.key, key.serialize_pem().as_bytes(), true)?;
+        write_atomic(&tls.cert, cert.pem().as_bytes(), false)
+    }
+
+    /// Starts checking the certificate, it is issued right away when missing, self-signed, not
+    /// covering every name in `tls.acme.names` or close to expiring
+    pub fn start(tls: &TlsConfig, acme: &AcmeConfig, challenges: Arc<Challenges>) -> Result<Acme, AcmeError> {
+        let issuer = Issuer {
+            agent: agent(acme)?,
+            config: acme.clone(),
+            cert: tls.cert.clone(),
+            key: tls.key.clone(),
+            challenges,
+        };
+
+        let (stop, stopped) = channel();
+        let thread = thread::Builder::new().name("acme-thread".to_string())
+            .spawn(move || renew_loop(issuer, stopped))
+            .map_err(|e| AcmeError::Io(tls.cert.clone(), e))?;
+        Ok(Acme { stop: Mutex::new(Some(stop)), thread: Mutex::new(Some(thread)) })
+    }
+
+    /// Stops renewing, an issuance in progress is abandoned at its next step
+    pub fn close(&self) {
+        drop(self.stop.lock().unwrap().take());
+        if let Some(t) = self.thread.lock().unwrap().take() {
+            if t.join().is_err() {
+                log::warn!("acme thread panicked");
+            }
+        }
+    }
+}
+
+//everything an issuance needs, owned by the renewal thread
+struct Issuer {
+    agent: ureq::Agent,
+    config: AcmeConfig,
+    cert: PathBuf,
+    key: PathBuf,
+    challenges: Arc<Challenges>,
+}
+
+fn renew_loop(issuer: Issuer, stopped: Receiver<()>) {
+    let mut wait = Duration::ZERO;
+    loop {
+        if sleep(&stopped, wait).is_err() {
+            break;
+        }
+        wait = match renewal_due(&issuer.cert, &issuer.config) {
+            Some(until) => until.min(CHECK_INTERVAL),
+            None => match issuer.issue(&stopped) {
+                Ok(()) => {
+                    log::info!("obtained a certificate for {} from {}", issuer.config.names.join(", "), issuer.config.directory);
+                    //the next check works out when it is due, the CA may have issued something
+                    //shorter lived than renew_before_days
+                    Duration::ZERO
+                },
+                Err(AcmeError::Stopped) => break,
+                Err(e) => {
+                    log::error!("unable to obtain a certificate, trying again in {}s: {}", issuer.config.retry_secs, e);
+                    Duration::from_secs(issuer.config.retry_secs)
+                },
+            },
+        };
+    }
+    log::info!("acme loop exiting");
+}
+
+//Err once the handle has been closed
+fn sleep(stopped: &Receiver<()>, wait: Duration) -> Result<(), AcmeError> {
+    match stopped.recv_timeout(wait) {
+        Err(RecvTimeoutError::Timeout) => Ok(()),
+        _ => Err(AcmeError::Stopped),
+    }
+}
+
+impl Issuer {
+    //RFC 8555 7.4: order, prove control of every name, finalize with our CSR and download
+    fn issue(&self, stopped: &Receiver<()>) -> Result<(), AcmeError> {
+        let account_key = account_key(&self.config.state_dir)?;
+        let mut account = Account::open(self.agent.clone(), &self.config.directory, &account_key, &self.config.contact)?;
+        let (order_url, order) = account.new_order(&self.config.names)?;
+
+        for url in &order.authorizations {
+            let authorization = account.authorization(url)?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let challenge = authorization.challenges.iter()
+                .find(|c| c.kind == "http-01")
+                .ok_or_else(|| AcmeError::Protocol(format!("no http-01 challenge offered for {}", authorization.identifier.value)))?;
+
+            self.challenges.insert(&challenge.token, account.key_authorization(&challenge.token));
+            let validated = account.respond(&challenge.url)
+                .and_then(|_| poll(stopped, &["pending"], || account.authorization(url).map(|a| a.status)));
+            self.challenges.remove(&challenge.token);
+            match validated?.as_str() {
+                "valid" => log::info!("validated {}", authorization.identifier.value),
+                status => return Err(AcmeError::Protocol(format!("authorization for {} is {}", authorization.identifier.value, status))),
+            }
+        }
+
+        let status = poll(stopped, &["pending"], || account.order(&order_url).map(|o| o.status))?;
+        if status != "ready" {
+            return Err(AcmeError::Protocol(format!("order {} is {} instead of ready", order_url, status)));
+        }
+
+        let key = KeyPair::generate()?;
+        let csr = CertificateParams::new(self.config.names.clone())?.serialize_request(&key)?;
+        account.finalize(&order.finalize, csr.der())?;
+        poll(stopped, &["ready", "processing"], || account.order(&order_url).map(|o| o.status))?;
+        let order = account.order(&order_url)?;
+        let certificate = match (order.status.as_str(), &order.certificate) {
+            ("valid", Some(url)) => account.certificate(url)?,
+            (status, _) => return Err(AcmeError::Protocol(format!("order {} is {}", order_url, status))),
+        };
+
+        //the key goes first, until the certificate follows the TLS watcher keeps the old pair
+        write_atomic(&self.key, key.serialize_pem().as_bytes(), true)?;
+        write_atomic(&self.cert, certificate.as_bytes(), false)
+    }
+}
+
+//checks a status until it is no longer one of the `in_progress` ones
+fn poll<F: FnMut() -> Result<String, AcmeError>>(stopped: &Receiver<()>, in_progress: &[&str], mut status: F) -> Result<String, AcmeError> {
+    for _ in 0..POLL_ATTEMPTS {
+        let current = status()?;
+        if !in_progress.contains(&current.as_str()) {
+            return Ok(current);
+        }
+        sleep(stopped, POLL_INTERVAL)?;
+    }
+    Err(AcmeError::Protocol(format!("still {} after {} checks", in_progress.join("/"), POLL_ATTEMPTS)))
+}
+
+//None when the certificate should be issued now, otherwise how long until it should be. Short
+//lived certificates are renewed a third of their lifetime before they expire
+fn renewal_due(cert: &Path, config: &AcmeConfig) -> Option<Duration> {
+    let der = tls::load_certs(cert).ok()?.into_iter().next()?;
+    let (_, cert) = X509Certificate::from_der(&der).ok()?;
+    if cert.issuer() == cert.subject() {
+        return None;
+    }
+    let names = match cert.subject_alternative_name() {
+        Ok(Some(ext)) => ext.value.general_names.iter()
+            .filter_map(|n| match n {
+                GeneralName::DNSName(s) => Some(s.to_ascii_lowercase()),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
+        _ => return None,
+    };
+    if !config.names.iter().all(|n| names.contains(&n.to_ascii_lowercase())) {
+        return None;
+    }
+
+    let validity = cert.validity();
+    let lifetime = (validity.not_after.timestamp() - validity.not_before.timestamp()).max(0) as u64;
+    let window = (config.renew_before_days * 24 * 60 * 60).min(lifetime / 3);
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs() as i64;
+    let renew_at = validity.not_after.timestamp() - window as i64;
+    if renew_at <= now {
+        return None;
+    }
+    Some(Duration::from_secs((renew_at - now) as u64))
+}
+
+//a P-256 key in PKCS#8, generated on first use
+fn account_key(state_dir: &Path) -> Result<Vec<u8>, AcmeError> {
+    let path = state_dir.join("account.key");
+    if path.is_file() {
+        return match tls::load_key(&path).map_err(AcmeError::Tls)? {
+            rustls::pki_types::PrivateKeyDer::Pkcs8(k) => Ok(k.secret_pkcs8_der().to_vec()),
+            _ => Err(AcmeError::Key(format!("{} is not a PKCS#8 key", path.display()))),
+        };
+    }
+
+    let rng = SystemRandom::new();
+    let key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).map_err(|e| AcmeError::Key(e.to_string()))?;
+    let pem = KeyPair::try_from(key.as_ref())?.serialize_pem();
+    write_atomic(&path, pem.as_bytes(), true)?;
+    log::info!("created a new ACME account key in {}", path.display());
+    Ok(key.as_ref().to_vec())
+}
+
+//written next to the target and renamed over it so readers never see half a file, keys only
+//readable by us
+fn write_atomic(path: &Path, contents: &[u8], private: bool) -> Result<(), AcmeError> {
+    let mut staged = path.as_os_str().to_owned();
+    staged.push(".new");
+    let staged = PathBuf::from(staged);
+    let io = |e| AcmeError::Io(path.to_path_buf(), e);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(&staged).map_err(io)?;
+    file.write_all(contents).and_then(|_| file.sync_all()).map_err(io)?;
+    fs::rename(&staged, path).map_err(io)
+}
+
+//the usual web roots plus tls.acme.directory_ca
+fn agent(config: &AcmeConfig) -> Result<ureq::Agent, AcmeError> {
+    let mut roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    if let Some(ca) = &config.directory_ca {
+        for cert in tls::load_certs(ca).map_err(AcmeError::Tls)? {
+            roots.add(cert).map_err(|e| AcmeError::Tls(TlsError::Rustls(e)))?;
+        }
+    }
+    let tls = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| AcmeError::Tls(TlsError::Rustls(e)))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(ureq::AgentBuilder::new()
+        .tls_config(Arc::new(tls))
+        .timeout(Duration::from_secs(30))
+        .user_agent(concat!("webserv/", env!("CARGO_PKG_VERSION")))
+        .build())
+}
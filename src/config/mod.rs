@@ -0,0 +1,808 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+
+use crate::http::is_token;
+
+//These are the values the server used to have hard-coded as statics in lib.rs, they are still
+//what you get when no config file is passed or a section is left out of it
+static DEFAULT_BIND_ADDR: &str = "127.0.0.1:8080";
+static DEFAULT_DOC_ROOT: &str = "./html/";
+static DEFAULT_INDEX: &str = "index.html";
+static DEFAULT_NOTFOUND_PAGE: &str = "404.html";
+
+//how a response is delimited and whether the connection lives on, hosts.headers can't override them
+static FRAMING_HEADERS: [&str; 5] = ["Content-Length", "Transfer-Encoding", "Connection", "Keep-Alive", "Upgrade"];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(p, e) => write!(f, "unable to read config file {}: {}", p.display(), e),
+            ConfigError::Parse(p, e) => write!(f, "invalid config file {}: {}", p.display(), e),
+            ConfigError::Invalid(s) => write!(f, "invalid config: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Top level server configuration, loaded from the TOML file passed with `-c/--config`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Used when no `-v` flags are passed on the command line
+    pub log_level: Option<LevelFilter>,
+    pub server: ServerConfig,
+    /// Status code -> page, relative to the doc root
+    #[serde(deserialize_with = "deserialize_error_pages")]
+    pub error_pages: BTreeMap<u16, PathBuf>,
+    pub cache: CacheConfig,
+    pub http: HttpConfig,
+    pub http2: Http2Config,
+    pub mime: MimeConfig,
+    /// HTTPS listeners, left out entirely for a plain HTTP only server
+    pub tls: Option<TlsConfig>,
+    /// Name-based virtual hosts, requests for names none of them claims get the settings above
+    pub hosts: Vec<HostConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Vec<SocketAddr>,
+    pub doc_root: PathBuf,
+    /// Tried in order when a directory is requested
+    pub index: Vec<String>,
+    /// Seconds open connections get to finish on shutdown before they are cut
+    pub shutdown_grace: u64,
+    /// Answer requests without a Host with 400 and ones for a name no `[[hosts]]` entry claims
+    /// with 421, instead of serving them from the default host
+    pub strict_hosts: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Upper bound on the bytes held by the FileCache across all entries
+    pub max_bytes: u64,
+    /// Upper bound on the number of files held by the FileCache
+    pub max_entries: usize,
+    /// Files larger than this are never cached, they are streamed from disk on every request
+    pub max_file_size: u64,
+    /// Seconds a file stays cached before it is read from disk again, even if no change was noticed
+    pub ttl: Option<u64>,
+    /// How long file change notifications are debounced before cached entries are dropped
+    pub watch_delay_ms: u64,
+    /// Seconds between logging the cache counters at info level
+    pub stats_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Requests whose head (request line + headers) is larger than this are answered with 431
+    pub max_header_size: usize,
+    /// Seconds a connection may sit idle waiting for its next request before we close it
+    pub keep_alive_timeout: u64,
+    /// Requests served on a single connection before it is closed, 1 disables keep-alive
+    pub max_requests: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http2Config {
+    /// Offer `h2` in ALPN on the HTTPS listeners, only takes effect on restart
+    pub enabled: bool,
+    /// Speak HTTP/2 on the plain listeners to clients that open with the prior-knowledge preface
+    pub h2c: bool,
+    /// Streams a client may have open on one connection at a time
+    pub max_concurrent_streams: u32,
+    /// Bytes a client may send on a stream before it has to wait for us to take them
+    pub initial_window_size: u32,
+    /// Same as initial_window_size for all the streams of a connection together
+    pub connection_window_size: u32,
+    /// Largest frame payload we accept
+    pub max_frame_size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MimeConfig {
+    /// Sent for files whose extension isn't known
+    pub default_type: String,
+    /// Add `; charset=utf-8` to text types
+    pub utf8_charset: bool,
+    /// Send `X-Content-Type-Options: nosniff` with every response
+    pub nosniff: bool,
+    /// Extension (without the dot) -> media type, extends and overrides the built-in table
+    pub types: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub listen: Vec<SocketAddr>,
+    /// PEM certificate chain, leaf first
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key: PathBuf,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// Seconds a client gets to complete the handshake
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+    /// Picked by the name the client asks for (SNI), `cert`/`key` above serve every other name
+    #[serde(default)]
+    pub certificates: Vec<SniCertConfig>,
+    /// How long changes to certificate and key files are debounced before they are loaded
+    #[serde(default = "default_tls_watch_delay")]
+    pub watch_delay_ms: u64,
+    /// Ask clients for a certificate and require one for some or all paths
+    pub client_auth: Option<ClientAuthConfig>,
+    /// Plain HTTP listeners that send everything (bar a few paths) over to HTTPS
+    pub redirect: Option<RedirectConfig>,
+    /// Send `Strict-Transport-Security` with every HTTPS response
+    pub hsts: Option<HstsConfig>,
+    /// Obtain and renew `cert`/`key` from an ACME CA, answering HTTP-01 challenges on the plain listeners
+    pub acme: Option<AcmeConfig>,
+    /// HTTP/3 on UDP listeners with the same certificates, advertised to HTTPS clients with `Alt-Svc`
+    pub http3: Option<Http3Config>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthConfig {
+    /// PEM bundle of the CAs client certificates have to chain to
+    pub ca: PathBuf,
+    /// URL path prefixes that need a verified client certificate, `/` for everything
+    #[serde(default = "default_client_auth_paths")]
+    pub paths: Vec<String>,
+    /// When not empty only certificates whose subject or one of whose SANs is listed get in
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub listen: Vec<SocketAddr>,
+    /// 301 or 308 for GET and HEAD, other methods always get a 308 so they aren't turned into a GET
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    /// Port put in the `Location`, the first `tls.listen` port when not set and left out when 443
+    pub https_port: Option<u16>,
+    /// URL path prefixes served from the doc root as usual instead of being redirected
+    #[serde(default = "default_redirect_exceptions")]
+    pub exceptions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HstsConfig {
+    /// Seconds browsers remember to only use HTTPS
+    #[serde(default = "default_hsts_max_age")]
+    pub max_age: u64,
+    #[serde(default)]
+    pub include_subdomains: bool,
+    /// Asks to be put on the browsers' preload list, which needs include_subdomains and a year of max_age
+    #[serde(default)]
+    pub preload: bool,
+}
+
+impl HstsConfig {
+    /// Value of the `Strict-Transport-Security` header
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// Directory URL of the CA
+    pub directory: String,
+    /// Names the certificate is issued for, the first one becomes its subject
+    pub names: Vec<String>,
+    /// Account contact URLs, `mailto:ops@example.com`
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Where the account key is kept between runs
+    pub state_dir: PathBuf,
+    /// Days before expiry the certificate is renewed
+    #[serde(default = "default_acme_renew_before_days")]
+    pub renew_before_days: u64,
+    /// Seconds to wait after a failed issuance before trying again
+    #[serde(default = "default_acme_retry_secs")]
+    pub retry_secs: u64,
+    /// PEM bundle trusted for the directory on top of the usual roots, for test CAs such as Pebble
+    pub directory_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http3Config {
+    /// UDP addresses QUIC is served on, usually the ports of tls.listen
+    pub listen: Vec<SocketAddr>,
+    /// Port advertised in `Alt-Svc`, the first http3.listen port when not set
+    pub alt_svc_port: Option<u16>,
+    /// Seconds clients may remember the advertisement
+    #[serde(default = "default_http3_alt_svc_max_age")]
+    pub alt_svc_max_age: u64,
+    /// Request streams a client may have open on one connection at a time
+    #[serde(default = "default_http3_max_concurrent_streams")]
+    pub max_concurrent_streams: u32,
+    /// Seconds a connection that carries nothing is kept before it is dropped
+    #[serde(default = "default_http3_idle_timeout")]
+    pub idle_timeout: u64,
+}
+
+/// A virtual host, what it leaves out comes from the top level settings
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// Matched against the Host of a request, `*.example.com` covers a single label under example.com
+    pub names: Vec<String>,
+    pub doc_root: PathBuf,
+    /// Replaces server.index
+    pub index: Option<Vec<String>>,
+    /// Added to the top level [error_pages], relative to this host's doc root like those are
+    #[serde(default, deserialize_with = "deserialize_error_pages")]
+    pub error_pages: BTreeMap<u16, PathBuf>,
+    /// Sent with every response for this host
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Replaces the paths and allow list of tls.client_auth for this host, its CA still applies
+    pub client_auth: Option<HostClientAuthConfig>,
+    /// Serves the requests no host claims (unless server.strict_hosts), at most one host can be it
+    #[serde(default)]
+    pub default: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostClientAuthConfig {
+    #[serde(default = "default_client_auth_paths")]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniCertConfig {
+    /// Host names served with this pair, `*.example.com` covers a single label under example.com
+    pub names: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+fn default_tls_watch_delay() -> u64 {
+    5000
+}
+
+fn default_client_auth_paths() -> Vec<String> {
+    vec!["/".to_string()]
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn default_redirect_exceptions() -> Vec<String> {
+    vec!["/.well-known/acme-challenge/".to_string()]
+}
+
+fn default_acme_renew_before_days() -> u64 {
+    30
+}
+
+fn default_acme_retry_secs() -> u64 {
+    3600
+}
+
+fn default_http3_alt_svc_max_age() -> u64 {
+    86_400
+}
+
+fn default_http3_max_concurrent_streams() -> u32 {
+    100
+}
+
+fn default_http3_idle_timeout() -> u64 {
+    30
+}
+
+//one year, the shortest the preload list accepts
+fn default_hsts_max_age() -> u64 {
+    31_536_000
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let mut error_pages = BTreeMap::new();
+        error_pages.insert(404, PathBuf::from(DEFAULT_NOTFOUND_PAGE));
+
+        Config {
+            log_level: None,
+            server: ServerConfig::default(),
+            error_pages,
+            cache: CacheConfig::default(),
+            http: HttpConfig::default(),
+            http2: Http2Config::default(),
+            mime: MimeConfig::default(),
+            tls: None,
+            hosts: Vec::new(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen: vec![DEFAULT_BIND_ADDR.parse().unwrap()],
+            doc_root: PathBuf::from(DEFAULT_DOC_ROOT),
+            index: vec![DEFAULT_INDEX.to_string()],
+            shutdown_grace: 30,
+            strict_hosts: false,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            max_bytes: 64 * 1024 * 1024,
+            max_entries: 1024,
+            max_file_size: 8 * 1024 * 1024,
+            ttl: None,
+            watch_delay_ms: 5000,
+            stats_interval: None,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            max_header_size: 16 * 1024,
+            keep_alive_timeout: 5,
+            max_requests: 100,
+        }
+    }
+}
+
+//the window and frame sizes are RFC 9113's initial values
+impl Default for Http2Config {
+    fn default() -> Http2Config {
+        Http2Config {
+            enabled: true,
+            h2c: false,
+            max_concurrent_streams: 100,
+            initial_window_size: 65_535,
+            connection_window_size: 65_535,
+            max_frame_size: 16_384,
+        }
+    }
+}
+
+impl Default for MimeConfig {
+    fn default() -> MimeConfig {
+        MimeConfig {
+            default_type: "application/octet-stream".to_string(),
+            utf8_charset: true,
+            nosniff: true,
+            types: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+
+        let config: Config = toml::from_str(&raw)
+            .map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let tls_listen = self.tls.as_ref().map(|t| t.listen.as_slice()).unwrap_or_default();
+        if self.server.listen.is_empty() && tls_listen.is_empty() {
+            return Err(ConfigError::Invalid("server.listen or tls.listen must contain at least one address".to_string()));
+        }
+        if let Some(addr) = tls_listen.iter().find(|a| a.port() != 0 && self.server.listen.contains(a)) {
+            return Err(ConfigError::Invalid(format!("{} is in both server.listen and tls.listen", addr)));
+        }
+
+        if !self.server.doc_root.is_dir() {
+            return Err(ConfigError::Invalid(format!(
+                "server.doc_root {} is not a directory",
+                self.server.doc_root.display()
+            )));
+        }
+
+        if self.server.index.iter().any(|i| i.is_empty() || i.contains('/')) {
+            return Err(ConfigError::Invalid("server.index entries must be plain file names".to_string()));
+        }
+
+        for (code, page) in &self.error_pages {
+            if !(400..600).contains(code) {
+                return Err(ConfigError::Invalid(format!("error_pages.{} is not an error status code", code)));
+            }
+            if page.is_absolute() {
+                return Err(ConfigError::Invalid(format!(
+                    "error_pages.{} must be relative to the doc root, got {}",
+                    code,
+                    page.display()
+                )));
+            }
+        }
+
+        if self.cache.max_file_size > self.cache.max_bytes {
+            return Err(ConfigError::Invalid("cache.max_file_size cannot be larger than cache.max_bytes".to_string()));
+        }
+
+        if self.cache.max_entries == 0 {
+            return Err(ConfigError::Invalid("cache.max_entries must be at least 1".to_string()));
+        }
+
+        if self.cache.watch_delay_ms == 0 {
+            return Err(ConfigError::Invalid("cache.watch_delay_ms must be at least 1".to_string()));
+        }
+
+        if self.cache.stats_interval == Some(0) {
+            return Err(ConfigError::Invalid("cache.stats_interval must be at least 1 second".to_string()));
+        }
+
+        if self.cache.ttl == Some(0) {
+            return Err(ConfigError::Invalid("cache.ttl must be at least 1 second, leave it out to disable expiry".to_string()));
+        }
+
+        if self.http.max_header_size < 1024 {
+            return Err(ConfigError::Invalid("http.max_header_size must be at least 1024 bytes".to_string()));
+        }
+
+        if self.http.keep_alive_timeout == 0 {
+            return Err(ConfigError::Invalid("http.keep_alive_timeout must be at least 1 second".to_string()));
+        }
+
+        if self.http.max_requests == 0 {
+            return Err(ConfigError::Invalid("http.max_requests must be at least 1".to_string()));
+        }
+
+        if self.http2.max_concurrent_streams == 0 {
+            return Err(ConfigError::Invalid("http2.max_concurrent_streams must be at least 1".to_string()));
+        }
+        //RFC 9113 6.5.2 and 6.9.1
+        for (key, size) in [("initial_window_size", self.http2.initial_window_size), ("connection_window_size", self.http2.connection_window_size)] {
+            if size > i32::MAX as u32 {
+                return Err(ConfigError::Invalid(format!("http2.{} must be at most {}", key, i32::MAX)));
+            }
+        }
+        if !(16_384..=16_777_215).contains(&self.http2.max_frame_size) {
+            return Err(ConfigError::Invalid("http2.max_frame_size must be between 16384 and 16777215".to_string()));
+        }
+
+        if let Some(tls) = &self.tls {
+            for (key, path) in [("tls.cert", &tls.cert), ("tls.key", &tls.key)] {
+                //with ACME they are written on startup if they don't exist yet, only their directory has to
+                let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+                if tls.acme.is_some() && !path.exists() && !dir.is_none_or(Path::is_dir) {
+                    return Err(ConfigError::Invalid(format!("{} {} is not in an existing directory", key, path.display())));
+                }
+                if (tls.acme.is_none() || path.exists()) && !path.is_file() {
+                    return Err(ConfigError::Invalid(format!("{} {} is not a file", key, path.display())));
+                }
+            }
+            if tls.handshake_timeout == 0 {
+                return Err(ConfigError::Invalid("tls.handshake_timeout must be at least 1 second".to_string()));
+            }
+            if tls.watch_delay_ms == 0 {
+                return Err(ConfigError::Invalid("tls.watch_delay_ms must be at least 1".to_string()));
+            }
+            if let Some(auth) = &tls.client_auth {
+                if !auth.ca.is_file() {
+                    return Err(ConfigError::Invalid(format!("tls.client_auth.ca {} is not a file", auth.ca.display())));
+                }
+                if let Some(p) = auth.paths.iter().find(|p| !p.starts_with('/') || p.split('/').any(|c| c == "." || c == "..")) {
+                    return Err(ConfigError::Invalid(format!("tls.client_auth.paths entry `{}` must be an absolute URL path", p)));
+                }
+            }
+            if let Some(redirect) = &tls.redirect {
+                if redirect.listen.is_empty() {
+                    return Err(ConfigError::Invalid("tls.redirect.listen must contain at least one address".to_string()));
+                }
+                let taken = self.server.listen.iter().chain(&tls.listen);
+                if let Some(addr) = taken.filter(|a| a.port() != 0).find(|a| redirect.listen.contains(a)) {
+                    return Err(ConfigError::Invalid(format!("{} is in tls.redirect.listen and another listen list", addr)));
+                }
+                if redirect.status != 301 && redirect.status != 308 {
+                    return Err(ConfigError::Invalid(format!("tls.redirect.status must be 301 or 308, not {}", redirect.status)));
+                }
+                if redirect.https_port.is_none() && tls.listen.is_empty() {
+                    return Err(ConfigError::Invalid("tls.redirect.https_port is needed when tls.listen is empty".to_string()));
+                }
+                if let Some(p) = redirect.exceptions.iter().find(|p| !p.starts_with('/')) {
+                    return Err(ConfigError::Invalid(format!("tls.redirect.exceptions entry `{}` must be an absolute URL path", p)));
+                }
+            }
+            if let Some(acme) = &tls.acme {
+                if !acme.directory.starts_with("https://") {
+                    return Err(ConfigError::Invalid(format!("tls.acme.directory `{}` must be an https:// URL", acme.directory)));
+                }
+                if acme.names.is_empty() {
+                    return Err(ConfigError::Invalid("tls.acme.names must contain at least one name".to_string()));
+                }
+                //HTTP-01 can't prove control of a whole zone, so no wildcards
+                if let Some(name) = acme.names.iter().find(|n| !is_sni_name(n) || n.starts_with("*.")) {
+                    return Err(ConfigError::Invalid(format!("tls.acme.names entry `{}` is not a host name", name)));
+                }
+                let http_listeners = self.server.listen.len() + tls.redirect.as_ref().map_or(0, |r| r.listen.len());
+                if http_listeners == 0 {
+                    return Err(ConfigError::Invalid(
+                        "tls.acme needs server.listen or tls.redirect.listen to answer HTTP-01 challenges".to_string(),
+                    ));
+                }
+                if acme.retry_secs == 0 {
+                    return Err(ConfigError::Invalid("tls.acme.retry_secs must be at least 1".to_string()));
+                }
+                if let Some(ca) = acme.directory_ca.as_ref().filter(|c| !c.is_file()) {
+                    return Err(ConfigError::Invalid(format!("tls.acme.directory_ca {} is not a file", ca.display())));
+                }
+            }
+            if let Some(hsts) = &tls.hsts {
+                if hsts.preload && !(hsts.include_subdomains && hsts.max_age >= default_hsts_max_age()) {
+                    return Err(ConfigError::Invalid(
+                        "tls.hsts.preload needs include_subdomains and a max_age of at least 31536000".to_string(),
+                    ));
+                }
+            }
+            if let Some(http3) = &tls.http3 {
+                if http3.listen.is_empty() {
+                    return Err(ConfigError::Invalid("tls.http3.listen must contain at least one address".to_string()));
+                }
+                if http3.max_concurrent_streams == 0 {
+                    return Err(ConfigError::Invalid("tls.http3.max_concurrent_streams must be at least 1".to_string()));
+                }
+                if http3.idle_timeout == 0 {
+                    return Err(ConfigError::Invalid("tls.http3.idle_timeout must be at least 1 second".to_string()));
+                }
+            }
+            for sni in &tls.certificates {
+                if sni.names.is_empty() {
+                    return Err(ConfigError::Invalid(format!("tls.certificates entry for {} has no names", sni.cert.display())));
+                }
+                if let Some(name) = sni.names.iter().find(|n| !is_sni_name(n)) {
+                    return Err(ConfigError::Invalid(format!("tls.certificates name `{}` is not a host name or *.wildcard", name)));
+                }
+                for (key, path) in [("cert", &sni.cert), ("key", &sni.key)] {
+                    if !path.is_file() {
+                        return Err(ConfigError::Invalid(format!("tls.certificates {} {} is not a file", key, path.display())));
+                    }
+                }
+            }
+        }
+
+        self.validate_hosts()?;
+
+        if !is_media_type(&self.mime.default_type) {
+            return Err(ConfigError::Invalid(format!("mime.default_type `{}` is not a media type", self.mime.default_type)));
+        }
+
+        for (ext, media_type) in &self.mime.types {
+            if ext.is_empty() || ext.contains('.') {
+                return Err(ConfigError::Invalid(format!("mime.types key `{}` must be an extension without the dot", ext)));
+            }
+            if !is_media_type(media_type) {
+                return Err(ConfigError::Invalid(format!("mime.types.{} `{}` is not a media type", ext, media_type)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_hosts(&self) -> Result<(), ConfigError> {
+        if self.server.strict_hosts && self.hosts.is_empty() {
+            return Err(ConfigError::Invalid("server.strict_hosts needs at least one [[hosts]] entry".to_string()));
+        }
+        if self.hosts.iter().filter(|h| h.default).count() > 1 {
+            return Err(ConfigError::Invalid("only one [[hosts]] entry can be the default".to_string()));
+        }
+
+        let mut seen = BTreeSet::new();
+        for host in &self.hosts {
+            let first = match host.names.first() {
+                Some(n) => n,
+                None => return Err(ConfigError::Invalid(format!("hosts entry for {} has no names", host.doc_root.display()))),
+            };
+            for name in &host.names {
+                if !is_sni_name(name) {
+                    return Err(ConfigError::Invalid(format!("hosts name `{}` is not a lowercase host name or *.wildcard", name)));
+                }
+                if !seen.insert(name) {
+                    return Err(ConfigError::Invalid(format!("hosts name `{}` is claimed more than once", name)));
+                }
+            }
+            if !host.doc_root.is_dir() {
+                return Err(ConfigError::Invalid(format!("hosts.{}.doc_root {} is not a directory", first, host.doc_root.display())));
+            }
+            if let Some(index) = &host.index {
+                if index.is_empty() || index.iter().any(|i| i.is_empty() || i.contains('/')) {
+                    return Err(ConfigError::Invalid(format!("hosts.{}.index entries must be plain file names", first)));
+                }
+            }
+            for (code, page) in &host.error_pages {
+                if !(400..600).contains(code) || page.is_absolute() {
+                    return Err(ConfigError::Invalid(format!(
+                        "hosts.{}.error_pages.{} must be an error status code with a page relative to the doc root",
+                        first, code
+                    )));
+                }
+            }
+            for (name, value) in &host.headers {
+                //the framing is ours to decide, and a line break would start a header of the client's choosing
+                if !is_token(name) || FRAMING_HEADERS.iter().any(|f| f.eq_ignore_ascii_case(name)) {
+                    return Err(ConfigError::Invalid(format!("hosts.{}.headers can't set `{}`", first, name)));
+                }
+                if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+                    return Err(ConfigError::Invalid(format!("hosts.{}.headers.{} has control characters", first, name)));
+                }
+            }
+            if let Some(auth) = &host.client_auth {
+                if self.tls.as_ref().and_then(|t| t.client_auth.as_ref()).is_none() {
+                    return Err(ConfigError::Invalid(format!("hosts.{}.client_auth needs the CA from tls.client_auth", first)));
+                }
+                if let Some(p) = auth.paths.iter().find(|p| !p.starts_with('/') || p.split('/').any(|c| c == "." || c == "..")) {
+                    return Err(ConfigError::Invalid(format!("hosts.{}.client_auth.paths entry `{}` must be an absolute URL path", first, p)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//type/subtype with an optional ;parameter tail, just enough to catch typos
+fn is_media_type(s: &str) -> bool {
+    let essence = s.split(';').next().unwrap_or_default().trim();
+    match essence.split_once('/') {
+        Some((t, sub)) => !t.is_empty() && !sub.is_empty() && !sub.contains('/') && !essence.contains(' '),
+        None => false,
+    }
+}
+
+//lowercase DNS labels, optionally with a single leading `*.` wildcard label
+fn is_sni_name(s: &str) -> bool {
+    let host = s.strip_prefix("*.").unwrap_or(s);
+    !host.is_empty() && host.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && label.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    })
+}
+
+//TOML keys are always strings, so `404 = "404.html"` needs a hand so it ends up keyed by a status code
+fn deserialize_error_pages<'de, D>(deserializer: D) -> Result<BTreeMap<u16, PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw = BTreeMap::<String, PathBuf>::deserialize(deserializer)?;
+    raw.into_iter()
+        .map(|(k, v)| match k.parse::<u16>() {
+            Ok(code) => Ok((code, v)),
+            Err(_) => Err(serde::de::Error::custom(format!("error_pages key `{}` is not a status code", k))),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    //loads toml from a file, with `{root}` standing in for an existing doc root
+    fn load(toml: &str) -> Result<Config, ConfigError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webserv.toml");
+        fs::write(&path, toml.replace("{root}", dir.path().to_str().unwrap())).unwrap();
+        Config::load(&path)
+    }
+
+    static MINIMAL: &str = "[server]\nlisten = [\"127.0.0.1:0\"]\ndoc_root = \"{root}\"\n";
+
+    #[test]
+    fn minimal_file_loads_with_defaults() {
+        let config = load(MINIMAL).unwrap();
+        assert_eq!(config.server.listen, vec!["127.0.0.1:0".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(config.server.index, vec![DEFAULT_INDEX.to_string()]);
+        assert!(config.tls.is_none() && config.hosts.is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let cases = [
+            format!("colour = \"blue\"\n{}", MINIMAL),
+            MINIMAL.replace("doc_root", "docroot"),
+            format!("{}[cache]\nmax_entires = 10\n", MINIMAL),
+            format!("{}[logging]\nlevel = \"info\"\n", MINIMAL),
+        ];
+        for case in cases.iter() {
+            match load(case) {
+                Err(ConfigError::Parse(_, e)) => assert!(e.to_string().contains("unknown field"), "{:?}: {}", case, e),
+                other => panic!("{:?}: {:?}", case, other),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_listen_addresses_are_rejected() {
+        for listen in ["localhost:8080", "127.0.0.1", "127.0.0.1:http", "300.0.0.1:80"] {
+            let toml = MINIMAL.replace("127.0.0.1:0", listen);
+            let result = load(&toml);
+            assert!(matches!(result, Err(ConfigError::Parse(..))), "{}: {:?}", listen, result);
+        }
+        let result = load(&MINIMAL.replace("[\"127.0.0.1:0\"]", "[]"));
+        assert!(matches!(result, Err(ConfigError::Invalid(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn missing_doc_root_is_rejected() {
+        let result = load(&MINIMAL.replace("{root}", "/nonexistent/webserv"));
+        assert!(matches!(result, Err(ConfigError::Invalid(_))), "{:?}", result);
+        //a file isn't a doc root either
+        let result = load(&MINIMAL.replace("{root}", "{root}/webserv.toml"));
+        assert!(matches!(result, Err(ConfigError::Invalid(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn zero_sized_limits_are_rejected() {
+        let cases = [
+            "[cache]\nmax_entries = 0\n",
+            "[cache]\nttl = 0\n",
+            "[cache]\nwatch_delay_ms = 0\n",
+            "[http]\nmax_header_size = 0\n",
+            "[http]\nkeep_alive_timeout = 0\n",
+            "[http]\nmax_requests = 0\n",
+            "[http2]\nmax_concurrent_streams = 0\n",
+        ];
+        for case in cases {
+            let result = load(&format!("{}{}", MINIMAL, case));
+            assert!(matches!(result, Err(ConfigError::Invalid(_))), "{:?}: {:?}", case, result);
+        }
+    }
+}
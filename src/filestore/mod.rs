@@ -1,176 +1,372 @@
 use std::collections::HashMap;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock, Mutex};
 use std::thread;
 
 use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 use std::sync::mpsc::{ Receiver, channel};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use crate::config::CacheConfig;
+use crate::http::{MimeTypes, Source};
 
 type FileEntryGuard<T> = Arc<RwLock<T>>;
 
+/// What a reader gets back from the FileCache, the contents are either shared with the cache or
+/// an open handle for files too big to be cached
+#[derive(Debug)]
+pub struct CachedFile {
+    pub contents: Source,
+    pub content_type: Arc<str>,
+    /// Strong validator derived from the mtime and size the contents were read with
+    pub etag: Arc<str>,
+    pub modified: SystemTime,
+}
+
+/// Counters describing how the FileCache is doing, see `FileCache::stats()`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to stay under `cache.max_bytes`/`cache.max_entries`
+    pub evictions: u64,
+    /// Entries dropped because they outlived `cache.ttl`
+    pub expirations: u64,
+    /// Entries dropped because the file changed on disk
+    pub invalidations: u64,
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} entries, {} bytes, {} hits, {} misses, {} evicted, {} expired, {} invalidated",
+            self.entries, self.bytes, self.hits, self.misses, self.evictions, self.expirations, self.invalidations
+        )
+    }
+}
+
 #[derive(Debug)]
 enum FileEntryError {
     NeedsUpdate,
     EmptyFile,
-    NoFileEntry
 }
 #[derive(Clone, Debug)]
 pub struct FileEntry {
     file: FileEntryGuard<std::fs::File>,
-    contents: FileEntryGuard<Option<String>>,
+    //Bytes is reference counted, handing the contents to a reader doesn't copy the file
+    contents: FileEntryGuard<Option<Bytes>>,
+    //resolved once when the entry is opened rather than on every request
+    content_type: Arc<str>,
+    //(etag, mtime) of the file as it was when contents was read, a notify event drops the entry so these roll with the file
+    validators: FileEntryGuard<Option<(Arc<str>, SystemTime)>>,
+    //bytes held in contents and when they were read, for the cache limits and ttl
+    size: u64,
+    loaded: Option<SystemTime>,
+    //whether size has been added to CacheStats.bytes, only touched with the store locked
+    counted: bool,
+    //bumped on every hit, the least recently used entry is the first to be evicted
     last_accessed: FileEntryGuard<Option<std::time::SystemTime>>
 }
 
 impl FileEntry {
 
-    pub fn new(path: &str) -> FileEntry {
+    pub fn new(file: std::fs::File, content_type: Arc<str>) -> FileEntry {
         FileEntry {
-            file: Arc::new(RwLock::new(std::fs::File::open(path).unwrap())),
+            file: Arc::new(RwLock::new(file)),
             contents: Arc::new(RwLock::new(None)),
+            content_type,
+            validators: Arc::new(RwLock::new(None)),
+            size: 0,
+            loaded: None,
+            counted: false,
             last_accessed: Arc::new(RwLock::new(None))
         }
     }
 
-    fn get(&self) -> Result<String, FileEntryError>  {
+    fn get(&self) -> Result<CachedFile, FileEntryError>  {
         let access_time = *Arc::clone(&self.last_accessed).read().unwrap();
         match access_time {
             Some(_) => {
                 //file has been updated at somepoint
-                log::warn!("Cache hit");
-                match &*Arc::clone(&self.contents).read().unwrap() {
-                    Some(s) => Ok(s.clone()),
-                    None => Err(FileEntryError::EmptyFile)
-                }
+                log::debug!("Cache hit");
+                let contents = Arc::clone(&self.contents);
+                let validators = Arc::clone(&self.validators);
+                let result = match (&*contents.read().unwrap(), &*validators.read().unwrap()) {
+                    (Some(b), Some((etag, modified))) => Ok(CachedFile {
+                        contents: Source::Memory(b.clone()),
+                        content_type: Arc::clone(&self.content_type),
+                        etag: Arc::clone(etag),
+                        modified: *modified,
+                    }),
+                    _ => Err(FileEntryError::EmptyFile)
+                };
+                *Arc::clone(&self.last_accessed).write().unwrap() = Some(SystemTime::now());
+                result
             },
             None => {
-                log::warn!("Cache miss");
+                log::debug!("Cache miss");
                 Err(FileEntryError::NeedsUpdate)
             }
         }
     }
 
-    fn update(&mut self) {
+    //returns false when another reader already loaded the contents
+    fn update(&mut self) -> Result<bool, std::io::Error> {
         let f = Arc::clone(&self.file);
         let mut f = f.write().unwrap();
 
         let contents =  Arc::clone(&self.contents);
-        match contents.write().unwrap().as_mut() {
+        let loaded = match contents.write().unwrap().as_mut() {
             Some(_s) => {
-                //f.read_to_string(&mut s);
-                log::warn!("got Some(_) while updating an entry");
+                log::debug!("got Some(_) while updating an entry");
+                false
             },
             None => {
-                let mut buf = String::new();
-                f.read_to_string(&mut buf).unwrap();
-                self.contents = Arc::new(RwLock::new(Some(buf)));
+                //metadata from the handle we read, so the validators describe exactly these bytes
+                let meta = f.metadata()?;
+                let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+                let mut buf = Vec::new();
+                f.read_to_end(&mut buf)?;
+                self.size = buf.len() as u64;
+                self.contents = Arc::new(RwLock::new(Some(Bytes::from(buf))));
+                self.validators = Arc::new(RwLock::new(Some((make_etag(modified, meta.len()), modified))));
+                self.loaded = Some(SystemTime::now());
+                true
             }
         };
 
         *Arc::clone(&self.last_accessed).write().unwrap() = Some(std::time::SystemTime::now());
+        Ok(loaded)
+    }
+
+    fn counted_size(&self) -> u64 {
+        if self.counted { self.size } else { 0 }
     }
+
+    fn is_expired(&self, ttl: Option<u64>) -> bool {
+        match (self.loaded, ttl) {
+            (Some(loaded), Some(ttl)) => loaded.elapsed().is_ok_and(|age| age > Duration::from_secs(ttl)),
+            _ => false,
+        }
+    }
+}
+
+//"<mtime in ns>-<size>" both in hex, the same file contents always give the same tag
+fn make_etag(modified: SystemTime, len: u64) -> Arc<str> {
+    let nanos = modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    Arc::from(format!("\"{:x}-{:x}\"", nanos, len))
 }
 
 type StoreGuard<T> = Arc<Mutex<T>>;
+type Store = StoreGuard< HashMap<String, FileEntryGuard<FileEntry>> >;
+
+/// Keys are canonical absolute paths, the watcher is registered on the canonical doc root so the
+/// paths in its events line up with them
 #[allow(dead_code)]
 pub struct FileCache {
-    store: StoreGuard< HashMap<String, FileEntryGuard<FileEntry>> >,
+    store: Store,
+    stats: StoreGuard<CacheStats>,
     notify_dir: String,
-    notify_watcher: RecommendedWatcher,
-    notify_thread: std::thread::JoinHandle<()>
+    mime: MimeTypes,
+    limits: CacheConfig,
+    //taken by close(), which can run while requests still hold the cache
+    notify_watcher: Mutex<Option<RecommendedWatcher>>,
+    notify_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
 }
 
 impl FileCache {
-    pub fn new(dir_watch: &str) -> FileCache {
+    /// Fails when the doc root can't be watched, it has gone missing or the inotify watch limit is
+    /// used up for instance
+    pub fn new(dir_watch: &str, mime: MimeTypes, limits: CacheConfig) -> Result<FileCache, std::io::Error> {
         let (tx, rx) = channel();
 
+        let store: Store = Arc::new(Mutex::new(HashMap::new()));
+        let stats = Arc::new(Mutex::new(CacheStats::default()));
+        let notify_store = Arc::clone(&store);
+        let notify_stats = Arc::clone(&stats);
 
+        let notify_dir = normalize(Path::new(dir_watch)).to_str().unwrap().to_string();
+        let mut watcher: RecommendedWatcher = notify::Watcher::new(tx, Duration::from_millis(limits.watch_delay_ms))
+            .map_err(std::io::Error::other)?;
+        watcher.watch(&notify_dir, RecursiveMode::Recursive).map_err(std::io::Error::other)?;
+        log::info!("started notify watcher on {}", &notify_dir);
 
-        let mut fc = FileCache {
-            store: Arc::new(Mutex::new(HashMap::new())),
-            notify_dir: dir_watch.to_string(),
-            notify_watcher: notify::Watcher::new(tx, Duration::from_secs(5)).unwrap(),
-            notify_thread: thread::Builder::new().name("notify-thread".to_string())
-                                .spawn(move || { FileCache::notify_loop(rx) }).unwrap(),
-        };
-        fc.notify_watcher.watch(&fc.notify_dir, RecursiveMode::Recursive).unwrap();
-        log::info!("started notify watcher on {}", &fc.notify_dir);
+        Ok(FileCache {
+            store,
+            stats,
+            notify_dir,
+            mime,
+            notify_watcher: Mutex::new(Some(watcher)),
+            notify_thread: Mutex::new(Some(thread::Builder::new().name("notify-thread".to_string())
+                                .spawn(move || { FileCache::notify_loop(rx, notify_store, notify_stats) })?)),
+            limits,
+        })
+    }
+
+    /// Hands out a file for serving, anything over `cache.max_file_size` skips the cache and is
+    /// returned as an open handle to be streamed
+    ///
+    /// `path` has to be canonical (see `normalize`) for change notifications to find the entry.
+    pub fn fetch(&self, path: &str) -> Result<CachedFile, std::io::Error> {
+        let file = std::fs::File::open(path)?;
+        let meta = file.metadata()?;
+        if meta.len() <= self.limits.max_file_size {
+            let fe = self.open(path, file);
+            return self.read(path, fe);
+        }
+
+        log::debug!("streaming {} ({} bytes) from disk", path, meta.len());
+        let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+        Ok(CachedFile {
+            contents: Source::Disk { file, len: meta.len() },
+            content_type: Arc::from(self.mime.resolve(Path::new(path))),
+            etag: make_etag(modified, meta.len()),
+            modified,
+        })
+    }
+
+    /// Stops watching the doc root and waits for the notify thread to exit, files can still be
+    /// fetched afterwards but changes to them go unnoticed
+    pub fn close(&self) {
+        drop(self.notify_watcher.lock().unwrap().take());
+        if let Some(t) = self.notify_thread.lock().unwrap().take() {
+            if t.join().is_err() {
+                log::warn!("notify thread panicked");
+            }
+        }
+    }
 
-        fc
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.store.lock().unwrap().len();
+        CacheStats { entries, ..*self.stats.lock().unwrap() }
     }
 
-    pub fn open(&self, path: &str) {
-        let fe = FileEntry::new(&path.to_string());
+    //the entry for path, replacing it first if it outlived the ttl
+    fn open(&self, path: &str, file: std::fs::File) -> FileEntryGuard<FileEntry> {
         let store = Arc::clone(&self.store);
         let mut store = store.lock().unwrap();
-        store.entry(path.to_string()).or_insert(Arc::new(RwLock::new(fe)));
-    }
-    pub fn read(&self, path: &str) -> String {
-        match self.lookup(path) {
-            Ok(s) => return s,
-            Err(e) => {
-                match e {
-                    FileEntryError::NeedsUpdate => {
-                        let store = Arc::clone(&self.store);
-                        let store = store.lock().unwrap();
-
-                       let fe = Arc::clone(&store.get(path).unwrap());
-                       let mut fe = fe.write().unwrap();
-
-                        fe.update();
-                        fe.get().unwrap()
-
-                    },
-                    FileEntryError::EmptyFile => return "".to_string(),
-                    FileEntryError::NoFileEntry => return "".to_string(),
-                }
-            }
 
+        let expired = match store.get(path) {
+            Some(fe) => fe.read().unwrap().is_expired(self.limits.ttl),
+            None => false,
+        };
+        if expired {
+            if let Some(fe) = store.remove(path) {
+                let mut stats = self.stats.lock().unwrap();
+                stats.expirations += 1;
+                stats.bytes -= fe.read().unwrap().counted_size();
+                log::debug!("expired {} from FileCache", path);
+            }
         }
+
+        let content_type = Arc::from(self.mime.resolve(std::path::Path::new(path)));
+        Arc::clone(store.entry(path.to_string()).or_insert_with(|| Arc::new(RwLock::new(FileEntry::new(file, content_type)))))
     }
-    fn lookup(&self, path: &str) -> Result<String, FileEntryError> {
-        let store = Arc::clone(&self.store);
-        let store = store.lock().unwrap();
 
-        let result = match store.get(path) {
-            Some(fe) => {
-                //this is mutable because the FileEntry.get() may update the contents
-                Some(fe)
+    fn read(&self, path: &str, fe: FileEntryGuard<FileEntry>) -> Result<CachedFile, std::io::Error> {
+        let r = fe.read().unwrap().get();
+        match r {
+            Ok(f) => {
+                self.stats.lock().unwrap().hits += 1;
+                Ok(f)
             },
-            //TODO: this shouldn't just return an empty string if there isn't a k,v pair
-            None => {
-                None
-            }
-        };
+            Err(FileEntryError::NeedsUpdate) | Err(FileEntryError::EmptyFile) => {
+                self.stats.lock().unwrap().misses += 1;
 
-        //log::debug!("hashmap contents at end of read {:#?}", store);
-
-        match result {
-            Some(fe) => {
-                let r = Arc::clone(&fe).read().unwrap().get();
-                match r {
-                    Ok(s) => {
-                        return Ok(s.clone())
-                    },
-                    Err(e) => {
-                        return Err(e)
-                    }
+                let (loaded, size, result) = {
+                    let mut fe = fe.write().unwrap();
+                    let loaded = fe.update()?;
+                    (loaded, fe.size, fe.get())
+                };
+
+                if loaded {
+                    self.account(path, &fe, size);
+                }
+
+                match result {
+                    Ok(f) => Ok(f),
+                    Err(e) => Err(std::io::Error::other(format!("{:?} after loading {}", e, path))),
                 }
-            },
-            None => {
-                return Err(FileEntryError::NoFileEntry)
             }
-        };
+        }
     }
 
-    fn invalidate_entry(&self, path: &str) -> Option<(String, FileEntryGuard<FileEntry>)>  {
+    //counts a freshly loaded entry against the limits and evicts least recently used entries to make room
+    fn account(&self, path: &str, fe: &FileEntryGuard<FileEntry>, size: u64) {
         let store = Arc::clone(&self.store);
         let mut store = store.lock().unwrap();
-        let value = store.remove_entry(&path.to_string());
 
-        return value
+        //it may have been invalidated while we were reading it, then it's not ours to count
+        match store.get(path) {
+            Some(current) if Arc::ptr_eq(current, fe) => (),
+            _ => return,
+        }
+
+        fe.write().unwrap().counted = true;
+        let mut stats = self.stats.lock().unwrap();
+        stats.bytes += size;
+
+        while stats.bytes > self.limits.max_bytes || store.len() > self.limits.max_entries {
+            //entries busy being loaded can't be inspected, they are about to be the most recently used anyway
+            let victim = store.iter()
+                .filter(|(k, _)| k.as_str() != path)
+                .filter_map(|(k, v)| {
+                    let v = v.try_read().ok()?;
+                    let accessed = *v.last_accessed.read().unwrap();
+                    Some((k.clone(), accessed))
+                })
+                .min_by_key(|(_, accessed)| *accessed)
+                .map(|(k, _)| k);
+
+            let victim = match victim {
+                Some(k) => k,
+                None => break,
+            };
+            if let Some(v) = store.remove(&victim) {
+                stats.bytes -= v.read().unwrap().counted_size();
+                stats.evictions += 1;
+                log::debug!("evicted {} from FileCache", victim);
+            }
+        }
+    }
+
+    //drops path and, when it is (or was) a directory, everything beneath it
+    fn invalidate(store: &Store, stats: &StoreGuard<CacheStats>, path: &Path) -> usize {
+        let path = normalize(path);
+        let mut store = store.lock().unwrap();
+        let keys = store.keys()
+            .filter(|k| Path::new(k).starts_with(&path))
+            .cloned()
+            .collect::<Vec<String>>();
+
+        let mut stats = stats.lock().unwrap();
+        for k in &keys {
+            if let Some(fe) = store.remove(k) {
+                stats.bytes -= fe.read().unwrap().counted_size();
+                stats.invalidations += 1;
+                log::info!("invalidated {} from FileCache", k);
+            }
+        }
+        keys.len()
     }
-    fn notify_loop(rx: Receiver<DebouncedEvent>) {
+
+    //when the watcher loses track of what changed the only safe thing left is to forget everything
+    fn flush(store: &Store, stats: &StoreGuard<CacheStats>) {
+        let mut store = store.lock().unwrap();
+        let mut stats = stats.lock().unwrap();
+        stats.invalidations += store.len() as u64;
+        stats.bytes = 0;
+        store.clear();
+        log::info!("flushed FileCache");
+    }
+
+    fn notify_loop(rx: Receiver<DebouncedEvent>, store: Store, stats: StoreGuard<CacheStats>) {
         log::info!("notify loop starting on thread-{:?}", std::thread::current());
         loop {
             match rx.recv() {
@@ -184,33 +380,337 @@ impl FileCache {
                         DebouncedEvent::NoticeRemove(_) => {
                             // do nothing, this is sent when a file is being removed
                         },
-                        DebouncedEvent::Create(p) => {
-                            if let Some(t) = crate::FILECACHE.invalidate_entry(p.to_str().unwrap()) {
-                                log::info!("invalidated {} from FileCache", &t.0)
-                            }
+                        DebouncedEvent::Create(p)
+                        | DebouncedEvent::Write(p)
+                        | DebouncedEvent::Remove(p) => {
+                            FileCache::invalidate(&store, &stats, &p);
                         },
-                        DebouncedEvent::Write(p) => {
-                            if let Some(t) = crate::FILECACHE.invalidate_entry(p.to_str().unwrap()) {
-                                log::info!("invalidated {} from FileCache", &t.0)
-                            }
+                        //the destination may have been cached under its old contents as well
+                        DebouncedEvent::Rename(from, to) => {
+                            FileCache::invalidate(&store, &stats, &from);
+                            FileCache::invalidate(&store, &stats, &to);
                         },
                         DebouncedEvent::Chmod(_) => log::debug!("received a Chmod event on watched dir, but we don't do anything!"),
-                        DebouncedEvent::Remove(p) => {
-                            if let Some(t) = crate::FILECACHE.invalidate_entry(p.to_str().unwrap()) {
-                                log::info!("invalidated {} from FileCache", &t.0)
-                            }
+                        DebouncedEvent::Rescan => FileCache::flush(&store, &stats),
+                        DebouncedEvent::Error(e, p) => {
+                            log::warn!("notify error on {:?}: {}", p, e);
+                            FileCache::flush(&store, &stats);
                         },
-                        DebouncedEvent::Rename(p, _) => {
-                            if let Some(t) = crate::FILECACHE.invalidate_entry(p.to_str().unwrap()) {
-                                log::info!("invalidated {} from FileCache", &t.0)
-                            }
-                        },
-                        DebouncedEvent::Rescan => log::debug!("received a Rescan event on watched dir, but we don't do anything!"),
-                        DebouncedEvent::Error(_, _) => log::debug!("received a Error event on watched dir, but we don't do anything!"),
                     }
                 },
-                Err(e) => log::info!("watch error: {:?}", e),
+                //the watcher is gone (FileCache dropped), nothing will ever arrive again
+                Err(_) => {
+                    log::info!("notify loop on thread-{:?} exiting", std::thread::current());
+                    return;
+                },
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// The form used for cache keys and watched paths, the canonical path when it exists and the
+/// canonical parent joined with the name when it has just been removed
+pub fn normalize(path: &Path) -> PathBuf {
+    if let Ok(p) = path.canonicalize() {
+        return p;
+    }
+    match (path.parent().map(|p| p.canonicalize()), path.file_name()) {
+        (Some(Ok(parent)), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::config::MimeConfig;
+
+    fn cache(root: &Path) -> FileCache {
+        cache_with(root, CacheConfig { watch_delay_ms: 50, ..CacheConfig::default() })
+    }
+
+    fn cache_with(root: &Path, limits: CacheConfig) -> FileCache {
+        FileCache::new(root.to_str().unwrap(), MimeTypes::new(&MimeConfig::default()), limits).unwrap()
+    }
+
+    fn is_cached(fc: &FileCache, path: &Path) -> bool {
+        fc.store.lock().unwrap().contains_key(&key(path))
+    }
+
+    //access times are wall clock, keep them apart so the eviction order is the one intended
+    fn tick() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    fn key(path: &Path) -> String {
+        normalize(path).to_str().unwrap().to_string()
+    }
+
+    fn contents(fc: &FileCache, path: &Path) -> Option<Vec<u8>> {
+        match fc.fetch(&key(path)).ok()?.contents {
+            Source::Memory(b) => Some(b.to_vec()),
+            Source::Disk { .. } => None,
+        }
+    }
+
+    //notify is asynchronous, so give the watcher a while to catch up before calling it stale
+    fn eventually(fc: &FileCache, path: &Path, expected: &[u8]) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let got = contents(fc, path);
+            if got.as_deref() == Some(expected) {
+                return;
+            }
+            if Instant::now() > deadline {
+                panic!("{} still served {:?}", path.display(), got.map(|b| String::from_utf8_lossy(&b).into_owned()));
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    //the watcher only sees changes made after it was registered
+    fn settle() {
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    #[test]
+    fn modified_file_is_reloaded() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("index.html");
+        fs::write(&file, "old").unwrap();
+
+        let fc = cache(root.path());
+        settle();
+        eventually(&fc, &file, b"old");
+
+        fs::write(&file, "new").unwrap();
+        eventually(&fc, &file, b"new");
+        assert!(fc.stats().invalidations >= 1);
+    }
+
+    #[test]
+    fn recreated_directory_drops_entries_beneath_it() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("docs");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("a.txt"), "a1").unwrap();
+        fs::write(dir.join("nested/b.txt"), "b1").unwrap();
+
+        let fc = cache(root.path());
+        settle();
+        eventually(&fc, &dir.join("a.txt"), b"a1");
+        eventually(&fc, &dir.join("nested/b.txt"), b"b1");
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("a.txt"), "a2").unwrap();
+        fs::write(dir.join("nested/b.txt"), "b2").unwrap();
+
+        eventually(&fc, &dir.join("a.txt"), b"a2");
+        eventually(&fc, &dir.join("nested/b.txt"), b"b2");
+    }
+
+    #[test]
+    fn renamed_directory_drops_entries_beneath_it() {
+        let root = tempfile::tempdir().unwrap();
+        let live = root.path().join("live");
+        let staged = root.path().join("staged");
+        fs::create_dir(&live).unwrap();
+        fs::create_dir(&staged).unwrap();
+        fs::write(live.join("page.html"), "v1").unwrap();
+        fs::write(staged.join("page.html"), "v2").unwrap();
+
+        let fc = cache(root.path());
+        settle();
+        eventually(&fc, &live.join("page.html"), b"v1");
+
+        fs::remove_dir_all(&live).unwrap();
+        fs::rename(&staged, &live).unwrap();
+        eventually(&fc, &live.join("page.html"), b"v2");
+    }
+
+    #[test]
+    fn file_renamed_over_cached_path_is_reloaded() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("app.js");
+        let tmp = root.path().join("app.js.tmp");
+        fs::write(&file, "one").unwrap();
+
+        let fc = cache(root.path());
+        settle();
+        eventually(&fc, &file, b"one");
+
+        fs::write(&tmp, "two").unwrap();
+        fs::rename(&tmp, &file).unwrap();
+        eventually(&fc, &file, b"two");
+    }
+
+    #[test]
+    fn non_canonical_root_still_invalidates() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("sub")).unwrap();
+        let file = root.path().join("style.css");
+        fs::write(&file, "body{}").unwrap();
+
+        let fc = cache(&root.path().join("sub/.."));
+        settle();
+        eventually(&fc, &file, b"body{}");
+
+        fs::write(&file, "p{}").unwrap();
+        eventually(&fc, &file, b"p{}");
+    }
+
+    #[test]
+    fn flush_forgets_everything() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a"), "a").unwrap();
+        fs::write(root.path().join("b"), "bb").unwrap();
+
+        let fc = cache(root.path());
+        eventually(&fc, &root.path().join("a"), b"a");
+        eventually(&fc, &root.path().join("b"), b"bb");
+        assert_eq!(fc.stats().bytes, 3);
+
+        FileCache::flush(&fc.store, &fc.stats);
+        let stats = fc.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.invalidations), (0, 0, 2));
+    }
+
+    #[test]
+    fn unwatchable_root_is_an_error() {
+        let root = tempfile::tempdir().unwrap();
+        let missing = root.path().join("missing");
+        let limits = CacheConfig::default();
+        assert!(FileCache::new(missing.to_str().unwrap(), MimeTypes::new(&MimeConfig::default()), limits).is_err());
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted_over_max_entries() {
+        let root = tempfile::tempdir().unwrap();
+        let [a, b, c] = ["a", "b", "c"].map(|n| root.path().join(n));
+        fs::write(&a, "aa").unwrap();
+        fs::write(&b, "bbb").unwrap();
+        fs::write(&c, "cccc").unwrap();
+
+        let fc = cache_with(root.path(), CacheConfig { max_entries: 2, ..CacheConfig::default() });
+        assert_eq!(contents(&fc, &a).unwrap(), b"aa");
+        tick();
+        assert_eq!(contents(&fc, &b).unwrap(), b"bbb");
+        tick();
+        //a hit makes a more recent than b
+        assert_eq!(contents(&fc, &a).unwrap(), b"aa");
+        tick();
+        assert_eq!(contents(&fc, &c).unwrap(), b"cccc");
+
+        assert!(is_cached(&fc, &a) && !is_cached(&fc, &b) && is_cached(&fc, &c));
+        let stats = fc.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (2, 6, 1));
+        assert_eq!((stats.hits, stats.misses), (1, 3));
+
+        //coming back costs a miss and the next least recently used entry
+        tick();
+        assert_eq!(contents(&fc, &b).unwrap(), b"bbb");
+        assert!(!is_cached(&fc, &a) && is_cached(&fc, &b) && is_cached(&fc, &c));
+        let stats = fc.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions, stats.misses), (2, 7, 2, 4));
+    }
+
+    #[test]
+    fn least_recently_used_are_evicted_over_max_bytes() {
+        let root = tempfile::tempdir().unwrap();
+        let [a, b, c] = ["a", "b", "c"].map(|n| root.path().join(n));
+        fs::write(&a, "1234").unwrap();
+        fs::write(&b, "5678").unwrap();
+        fs::write(&c, "abcdefghi").unwrap();
+
+        let fc = cache_with(root.path(), CacheConfig { max_bytes: 12, ..CacheConfig::default() });
+        contents(&fc, &a).unwrap();
+        tick();
+        contents(&fc, &b).unwrap();
+        tick();
+        assert_eq!(fc.stats().bytes, 8);
+
+        //9 more bytes only fit once both older entries are gone
+        contents(&fc, &c).unwrap();
+        assert!(!is_cached(&fc, &a) && !is_cached(&fc, &b) && is_cached(&fc, &c));
+        let stats = fc.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (1, 9, 2));
+
+        //files over max_file_size are streamed and never counted
+        let fc = cache_with(root.path(), CacheConfig { max_file_size: 4, ..CacheConfig::default() });
+        assert!(contents(&fc, &c).is_none());
+        assert_eq!(contents(&fc, &a).unwrap(), b"1234");
+        let stats = fc.stats();
+        assert_eq!((stats.entries, stats.bytes), (1, 4));
+    }
+
+    #[test]
+    fn entries_being_loaded_are_not_evicted() {
+        let root = tempfile::tempdir().unwrap();
+        let [a, b, c] = ["a", "b", "c"].map(|n| root.path().join(n));
+        for p in [&a, &b, &c] {
+            fs::write(p, "x").unwrap();
+        }
+
+        let fc = cache_with(root.path(), CacheConfig { max_entries: 2, ..CacheConfig::default() });
+        contents(&fc, &a).unwrap();
+        tick();
+        contents(&fc, &b).unwrap();
+        tick();
+
+        //a is the least recently used, but locked as if another request were reading it in
+        let busy = Arc::clone(&fc.store.lock().unwrap()[&key(&a)]);
+        let guard = busy.write().unwrap();
+        contents(&fc, &c).unwrap();
+        drop(guard);
+
+        assert!(is_cached(&fc, &a) && !is_cached(&fc, &b) && is_cached(&fc, &c));
+        let stats = fc.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (2, 2, 1));
+    }
+
+    #[test]
+    fn files_over_max_file_size_are_streamed_without_touching_the_cache() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("big.bin");
+        fs::write(&file, vec![7u8; 4096]).unwrap();
+
+        let fc = cache_with(root.path(), CacheConfig { max_file_size: 1024, ..CacheConfig::default() });
+        let before = fc.stats();
+        for _ in 0..2 {
+            match fc.fetch(&key(&file)).unwrap().contents {
+                Source::Disk { len, .. } => assert_eq!(len, 4096),
+                Source::Memory(_) => panic!("{} was read into memory", file.display()),
+            }
+        }
+        assert!(!is_cached(&fc, &file));
+        let after = fc.stats();
+        assert_eq!(
+            (after.entries, after.bytes, after.hits, after.misses, after.evictions),
+            (before.entries, before.bytes, before.hits, before.misses, before.evictions)
+        );
+    }
+
+    #[test]
+    fn expired_entry_is_read_again() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("page.html");
+        fs::write(&file, "first").unwrap();
+
+        //a long debounce keeps the watcher out of it, only the ttl can notice the change
+        let limits = CacheConfig { ttl: Some(1), watch_delay_ms: 60_000, ..CacheConfig::default() };
+        let fc = cache_with(root.path(), limits);
+        assert_eq!(contents(&fc, &file).unwrap(), b"first");
+        fs::write(&file, "second!").unwrap();
+        assert_eq!(contents(&fc, &file).unwrap(), b"first");
+
+        thread::sleep(Duration::from_millis(1200));
+        assert_eq!(contents(&fc, &file).unwrap(), b"second!");
+        let stats = fc.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.expirations), (1, 7, 1));
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::SeekFrom;
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+//how much of a streamed file is held in memory at once per connection
+static STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where the bytes of a representation live, files above the cache size limit stay on disk
+#[derive(Debug)]
+pub enum Source {
+    Memory(Bytes),
+    Disk { file: std::fs::File, len: u64 },
+}
+
+impl Source {
+    pub fn len(&self) -> u64 {
+        match self {
+            Source::Memory(b) => b.len() as u64,
+            Source::Disk { len, .. } => *len,
+        }
+    }
+
+    /// The whole representation as a response body
+    pub fn into_body(self) -> Body {
+        match self {
+            Source::Memory(b) => Body::Bytes(b),
+            Source::Disk { file, len } => Body::File {
+                file,
+                parts: vec![BodyPart::File { offset: 0, len }],
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BodyPart {
+    Bytes(Bytes),
+    /// A span of the body's file
+    File { offset: u64, len: u64 },
+}
+
+#[derive(Debug)]
+pub enum Body {
+    Bytes(Bytes),
+    /// Streamed off disk, the parts are written in order so literal bytes (multipart framing) can
+    /// be mixed in between spans of the file
+    File { file: std::fs::File, parts: Vec<BodyPart> },
+}
+
+impl Body {
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(b) => b.len() as u64,
+            Body::File { parts, .. } => parts.iter()
+                .map(|p| match p {
+                    BodyPart::Bytes(b) => b.len() as u64,
+                    BodyPart::File { len, .. } => *len,
+                })
+                .sum(),
+        }
+    }
+
+    /// Writes the body out, file spans go a chunk at a time so a slow client holds back our reads
+    /// instead of the whole file ending up buffered
+    pub async fn write_to<W: AsyncWrite + Unpin>(self, w: &mut W) -> Result<(), std::io::Error> {
+        match self {
+            Body::Bytes(b) => w.write_all(&b).await,
+            Body::File { file, parts } => {
+                let mut file = tokio::fs::File::from_std(file);
+                let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+
+                for part in parts {
+                    match part {
+                        BodyPart::Bytes(b) => w.write_all(&b).await?,
+                        BodyPart::File { offset, len } => {
+                            file.seek(SeekFrom::Start(offset)).await?;
+                            copy_exact(&mut file, w, len, &mut chunk).await?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(b: Bytes) -> Body {
+        Body::Bytes(b)
+    }
+}
+
+//the file shrinking under us is an error, we already promised the client a Content-Length
+async fn copy_exact<R, W>(r: &mut R, w: &mut W, len: u64, chunk: &mut [u8]) -> Result<(), std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = remaining.min(chunk.len() as u64) as usize;
+        let n = r.read(&mut chunk[..want]).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        w.write_all(&chunk[..n]).await?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
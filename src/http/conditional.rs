@@ -0,0 +1,176 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{HttpMethod, HttpRequest};
+
+/// Outcome of checking a request's preconditions against the current representation
+#[derive(Debug, PartialEq)]
+pub enum Precondition {
+    /// Serve the request as normal
+    Proceed,
+    /// 304, only ever for GET and HEAD
+    NotModified,
+    /// 412
+    Failed,
+}
+
+/// Evaluates If-Match, If-Unmodified-Since, If-None-Match and If-Modified-Since in the order
+/// RFC 9110 13.2.2 lays out
+pub fn evaluate(req: &HttpRequest, etag: &str, modified: SystemTime) -> Precondition {
+    let modified = truncate_to_secs(modified);
+    let is_get_or_head = req.method == HttpMethod::GET || req.method == HttpMethod::HEAD;
+
+    if let Some(if_match) = req.header("If-Match") {
+        if !etag_list_matches(if_match, etag, false) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = req.header("If-Unmodified-Since").and_then(parse_date) {
+        if modified > since {
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(if_none_match) = req.header("If-None-Match") {
+        if etag_list_matches(if_none_match, etag, true) {
+            return if is_get_or_head { Precondition::NotModified } else { Precondition::Failed };
+        }
+    } else if let Some(since) = req.header("If-Modified-Since").and_then(parse_date) {
+        if is_get_or_head && modified <= since {
+            return Precondition::NotModified;
+        }
+    }
+
+    Precondition::Proceed
+}
+
+/// `If-Range` (RFC 9110 13.1.5) holds either a strong ETag or a date, and only an exact match keeps the Range
+pub fn if_range_matches(value: &str, etag: &str, modified: SystemTime) -> bool {
+    let value = value.trim();
+    if value.starts_with('"') {
+        return value == etag;
+    }
+    match parse_date(value) {
+        Some(date) => date == truncate_to_secs(modified),
+        None => false,
+    }
+}
+
+//a list of entity-tags or `*`, weak comparison ignores the W/ prefix on either side
+fn etag_list_matches(list: &str, etag: &str, weak: bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+    list.split(',').map(|t| t.trim()).any(|t| {
+        if weak {
+            t.trim_start_matches("W/") == etag.trim_start_matches("W/")
+        } else {
+            !t.starts_with("W/") && !etag.starts_with("W/") && t == etag
+        }
+    })
+}
+
+fn parse_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.trim()).ok()
+}
+
+//HTTP dates only have second resolution, so mtimes have to be compared the same way
+fn truncate_to_secs(t: SystemTime) -> SystemTime {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + Duration::from_secs(d.as_secs()),
+        Err(_) => UNIX_EPOCH,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::http::{HeaderMap, ReqURI};
+
+    static ETAG: &str = "\"5f-a\"";
+
+    fn request(method: HttpMethod, headers: &[(&str, &str)]) -> HttpRequest {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(name, value);
+        }
+        HttpRequest::new(method, ReqURI::new("/".to_string(), PathBuf::new()), Some(map))
+    }
+
+    //sub-second mtimes, which have to compare equal to the date they are sent out as
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_600_000_000_750)
+    }
+
+    fn date(offset_secs: i64) -> String {
+        let secs = (1_600_000_000 + offset_secs) as u64;
+        httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    fn get(headers: &[(&str, &str)]) -> Precondition {
+        evaluate(&request(HttpMethod::GET, headers), ETAG, modified())
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        assert_eq!(get(&[("If-None-Match", ETAG)]), Precondition::NotModified);
+        assert_eq!(get(&[("If-None-Match", "W/\"5f-a\"")]), Precondition::NotModified);
+        assert_eq!(get(&[("If-None-Match", "\"other\", \"5f-a\"")]), Precondition::NotModified);
+        assert_eq!(get(&[("If-None-Match", "*")]), Precondition::NotModified);
+        assert_eq!(get(&[("If-None-Match", "\"other\"")]), Precondition::Proceed);
+        //a match on anything but GET and HEAD means the precondition failed
+        let put = request(HttpMethod::PUT, &[("If-None-Match", ETAG)]);
+        assert_eq!(evaluate(&put, ETAG, modified()), Precondition::Failed);
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        assert_eq!(get(&[("If-Match", ETAG)]), Precondition::Proceed);
+        assert_eq!(get(&[("If-Match", "*")]), Precondition::Proceed);
+        assert_eq!(get(&[("If-Match", "\"other\", \"5f-a\"")]), Precondition::Proceed);
+        assert_eq!(get(&[("If-Match", "W/\"5f-a\"")]), Precondition::Failed);
+        assert_eq!(get(&[("If-Match", "\"other\"")]), Precondition::Failed);
+        assert_eq!(evaluate(&request(HttpMethod::GET, &[("If-Match", "W/\"5f-a\"")]), "W/\"5f-a\"", modified()), Precondition::Failed);
+    }
+
+    #[test]
+    fn dates_compare_at_second_resolution() {
+        assert_eq!(get(&[("If-Modified-Since", &date(0))]), Precondition::NotModified);
+        assert_eq!(get(&[("If-Modified-Since", &date(60))]), Precondition::NotModified);
+        assert_eq!(get(&[("If-Modified-Since", &date(-1))]), Precondition::Proceed);
+        assert_eq!(get(&[("If-Unmodified-Since", &date(0))]), Precondition::Proceed);
+        assert_eq!(get(&[("If-Unmodified-Since", &date(-1))]), Precondition::Failed);
+        //a date that doesn't parse is ignored
+        assert_eq!(get(&[("If-Modified-Since", "yesterday")]), Precondition::Proceed);
+        assert_eq!(get(&[("If-Unmodified-Since", "yesterday")]), Precondition::Proceed);
+        //If-Modified-Since only applies to GET and HEAD
+        let options = request(HttpMethod::OPTIONS, &[("If-Modified-Since", &date(0))]);
+        assert_eq!(evaluate(&options, ETAG, modified()), Precondition::Proceed);
+    }
+
+    #[test]
+    fn etags_take_precedence_over_dates() {
+        //If-Match decides alone, an If-Unmodified-Since that would fail is not looked at
+        assert_eq!(get(&[("If-Match", ETAG), ("If-Unmodified-Since", &date(-1))]), Precondition::Proceed);
+        assert_eq!(get(&[("If-Match", "\"other\""), ("If-Unmodified-Since", &date(0))]), Precondition::Failed);
+        //and so does If-None-Match over If-Modified-Since
+        assert_eq!(get(&[("If-None-Match", "\"other\""), ("If-Modified-Since", &date(0))]), Precondition::Proceed);
+        assert_eq!(get(&[("If-None-Match", ETAG), ("If-Modified-Since", &date(-1))]), Precondition::NotModified);
+        //a failed If-Match wins over a matching If-None-Match
+        assert_eq!(get(&[("If-Match", "\"other\""), ("If-None-Match", ETAG)]), Precondition::Failed);
+    }
+
+    #[test]
+    fn if_range_needs_an_exact_match() {
+        assert!(if_range_matches(ETAG, ETAG, modified()));
+        assert!(if_range_matches(" \"5f-a\" ", ETAG, modified()));
+        assert!(!if_range_matches("\"other\"", ETAG, modified()));
+        //weak tags never match
+        assert!(!if_range_matches("W/\"5f-a\"", ETAG, modified()));
+        assert!(if_range_matches(&date(0), ETAG, modified()));
+        //a later date isn't good enough, it has to be the one we sent
+        assert!(!if_range_matches(&date(1), ETAG, modified()));
+        assert!(!if_range_matches(&date(-1), ETAG, modified()));
+        assert!(!if_range_matches("tomorrow", ETAG, modified()));
+    }
+}
@@ -0,0 +1,44 @@
+/// An ordered set of header fields with case-insensitive names
+///
+/// Names keep the case they were inserted with so responses go out looking the way we wrote them,
+/// but every lookup ignores case as RFC 7230 3.2 requires.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap {
+    fields: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap { fields: Vec::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Replaces any existing field with the same name
+    pub fn set<V: Into<String>>(&mut self, name: &str, value: V) {
+        let value = value.into();
+        match self.fields.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(name)) {
+            Some(field) => field.1 = value,
+            None => self.fields.push((name.to_string(), value)),
+        }
+    }
+
+    /// Adds to an existing field as a comma separated list, RFC 7230 3.2.2
+    pub fn append(&mut self, name: &str, value: &str) {
+        match self.fields.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(name)) {
+            Some(field) => {
+                field.1.push_str(", ");
+                field.1.push_str(value);
+            },
+            None => self.fields.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::config::MimeConfig;
+
+//extension -> media type, anything here can be replaced or added to with [mime.types] in the config
+static BUILTIN_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("iso", "application/x-iso9660-image"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+//non text/* types that are still text and should carry a charset
+static TEXTUAL_TYPES: [&str; 4] = ["application/json", "application/xml", "application/manifest+json", "image/svg+xml"];
+
+/// Resolves a file's `Content-Type` from its extension
+#[derive(Debug, Clone)]
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+    default_type: String,
+    utf8_charset: bool,
+}
+
+impl MimeTypes {
+    pub fn new(config: &MimeConfig) -> MimeTypes {
+        let mut types = BUILTIN_TYPES.iter()
+            .map(|(ext, t)| (ext.to_string(), t.to_string()))
+            .collect::<HashMap<String, String>>();
+        for (ext, t) in &config.types {
+            types.insert(ext.to_ascii_lowercase(), t.clone());
+        }
+
+        MimeTypes {
+            types,
+            default_type: config.default_type.clone(),
+            utf8_charset: config.utf8_charset,
+        }
+    }
+
+    pub fn resolve(&self, path: &Path) -> String {
+        let media_type = path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| self.types.get(&e.to_ascii_lowercase()))
+            .unwrap_or(&self.default_type);
+
+        if self.utf8_charset && is_textual(media_type) && !media_type.contains("charset=") {
+            format!("{}; charset=utf-8", media_type)
+        } else {
+            media_type.clone()
+        }
+    }
+}
+
+fn is_textual(media_type: &str) -> bool {
+    media_type.starts_with("text/") || TEXTUAL_TYPES.contains(&media_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(mime: &MimeTypes, name: &str) -> String {
+        mime.resolve(Path::new(name))
+    }
+
+    #[test]
+    fn builtin_table() {
+        let mime = MimeTypes::new(&MimeConfig::default());
+        let cases = [
+            ("index.html", "text/html; charset=utf-8"),
+            ("docs/page.htm", "text/html; charset=utf-8"),
+            ("logo.PNG", "image/png"),
+            ("photo.jpeg", "image/jpeg"),
+            ("font.woff2", "font/woff2"),
+            ("app.wasm", "application/wasm"),
+            ("release.tar.gz", "application/gzip"),
+            //no extension, an unknown one or a dotfile all get the default
+            ("README", "application/octet-stream"),
+            ("data.xyz", "application/octet-stream"),
+            (".htaccess", "application/octet-stream"),
+        ];
+        for (name, expected) in cases.iter() {
+            assert_eq!(resolve(&mime, name), *expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn charset_only_on_textual_types() {
+        let mime = MimeTypes::new(&MimeConfig::default());
+        let textual = [
+            ("style.css", "text/css"),
+            ("app.js", "text/javascript"),
+            ("module.mjs", "text/javascript"),
+            ("data.json", "application/json"),
+            ("app.js.map", "application/json"),
+            ("feed.xml", "application/xml"),
+            ("site.webmanifest", "application/manifest+json"),
+            ("icon.svg", "image/svg+xml"),
+        ];
+        for (name, media_type) in textual.iter() {
+            assert_eq!(resolve(&mime, name), format!("{}; charset=utf-8", media_type), "{}", name);
+        }
+        for name in ["image.png", "doc.pdf", "song.mp3", "app.wasm", "blob"] {
+            assert!(!resolve(&mime, name).contains("charset"), "{}", name);
+        }
+
+        let mime = MimeTypes::new(&MimeConfig { utf8_charset: false, ..MimeConfig::default() });
+        assert_eq!(resolve(&mime, "index.html"), "text/html");
+        assert_eq!(resolve(&mime, "data.json"), "application/json");
+    }
+
+    #[test]
+    fn config_types_extend_and_override() {
+        let mut config = MimeConfig { default_type: "text/plain".to_string(), ..MimeConfig::default() };
+        for (ext, media_type) in [
+            ("html", "application/xhtml+xml"),
+            ("md", "text/markdown; charset=iso-8859-1"),
+            ("Dat", "application/x-data"),
+            ("log", "text/x-log"),
+        ] {
+            config.types.insert(ext.to_string(), media_type.to_string());
+        }
+        let mime = MimeTypes::new(&config);
+
+        assert_eq!(resolve(&mime, "index.html"), "application/xhtml+xml");
+        //a charset given in the config is left alone
+        assert_eq!(resolve(&mime, "notes.md"), "text/markdown; charset=iso-8859-1");
+        assert_eq!(resolve(&mime, "records.DAT"), "application/x-data");
+        assert_eq!(resolve(&mime, "server.log"), "text/x-log; charset=utf-8");
+        //the rest of the built-in table is still there
+        assert_eq!(resolve(&mime, "logo.png"), "image/png");
+        assert_eq!(resolve(&mime, "unknown.xyz"), "text/plain; charset=utf-8");
+    }
+}
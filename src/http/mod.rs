@@ -1,30 +1,87 @@
-use std::collections::HashMap;
 use std::path::PathBuf;
 
-#[derive(Debug, PartialEq)]
+use crate::config::Config;
+
+mod body;
+mod conditional;
+mod headers;
+mod mime;
+mod range;
+mod reader;
+mod response;
+pub use body::{Body, BodyPart, Source};
+pub use conditional::Precondition;
+pub use headers::HeaderMap;
+pub use mime::MimeTypes;
+pub use range::{partial_response, RangeRequest};
+pub use reader::{ReadError, RequestReader};
+pub use response::HttpResponse;
+
+/// The RFC 9110 methods, plus whatever other token a client sent
+#[derive(Debug, PartialEq, Clone)]
 #[allow(dead_code)]
 pub enum HttpMethod {
     GET,
+    HEAD,
     POST,
-    UPDATE,
+    PUT,
     DELETE,
     CONNECT,
+    OPTIONS,
     TRACE,
-    HEAD,
-    OPTION,
+    PATCH,
+    Extension(String),
 }
 
-#[derive(Debug, PartialEq)]
+impl HttpMethod {
+    /// Method names are case-sensitive (RFC 9110 9.1), None means it isn't even a valid token
+    pub fn from_token(token: &str) -> Option<HttpMethod> {
+        let method = match token {
+            "GET" => HttpMethod::GET,
+            "HEAD" => HttpMethod::HEAD,
+            "POST" => HttpMethod::POST,
+            "PUT" => HttpMethod::PUT,
+            "DELETE" => HttpMethod::DELETE,
+            "CONNECT" => HttpMethod::CONNECT,
+            "OPTIONS" => HttpMethod::OPTIONS,
+            "TRACE" => HttpMethod::TRACE,
+            "PATCH" => HttpMethod::PATCH,
+            t if is_token(t) => HttpMethod::Extension(t.to_string()),
+            _ => return None,
+        };
+        Some(method)
+    }
+}
+
+/// Value of the `Allow` header, every method we actually serve
+pub static ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+//the versions we will answer, anything else gets a 505
+static SUPPORTED_PROTO_VERSIONS: [&str; 2] = ["HTTP/1.0", "HTTP/1.1"];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[allow(dead_code)]
 pub enum HttpStatusCode {
     Continue,
     HttpOk,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    NotModified,
+    PermanentRedirect,
     BadRequest,
     Unauthorized,
     Forbidden,
     NotFound,
+    MethodNotAllowed,
+    PreconditionFailed,
+    RangeNotSatisfiable,
+    MisdirectedRequest,
+    RequestHeaderFieldsTooLarge,
     InternalServerError,
     NotImplemented,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
 }
 
 impl HttpStatusCode {
@@ -32,12 +89,24 @@ impl HttpStatusCode {
         match *self {
             HttpStatusCode::Continue => (100, "Continue"),
             HttpStatusCode::HttpOk => (200, "OK"),
+            HttpStatusCode::NoContent => (204, "No content"),
+            HttpStatusCode::PartialContent => (206, "Partial content"),
+            HttpStatusCode::MovedPermanently => (301, "Moved permanently"),
+            HttpStatusCode::NotModified => (304, "Not modified"),
+            HttpStatusCode::PermanentRedirect => (308, "Permanent redirect"),
             HttpStatusCode::BadRequest => (400, "Bad request"),
             HttpStatusCode::Unauthorized => (401, "Unauthorized"),
             HttpStatusCode::Forbidden => (403, "Forbidden"),
             HttpStatusCode::NotFound => (404, "Not found"),
+            HttpStatusCode::MethodNotAllowed => (405, "Method not allowed"),
+            HttpStatusCode::PreconditionFailed => (412, "Precondition failed"),
+            HttpStatusCode::RangeNotSatisfiable => (416, "Range not satisfiable"),
+            HttpStatusCode::MisdirectedRequest => (421, "Misdirected request"),
+            HttpStatusCode::RequestHeaderFieldsTooLarge => (431, "Request header fields too large"),
             HttpStatusCode::InternalServerError => (500, "Internal server error"),
             HttpStatusCode::NotImplemented => (501, "Not implemented"),
+            HttpStatusCode::ServiceUnavailable => (503, "Service unavailable"),
+            HttpStatusCode::HttpVersionNotSupported => (505, "HTTP version not supported"),
         }
     }
 }
@@ -61,22 +130,19 @@ impl ReqURI {
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub req_uri: ReqURI,
-    pub proto_ver: String,
-    pub req_headers: Option<HashMap<String, String>>,
+    pub req_headers: Option<HeaderMap>,
 }
 
 impl HttpRequest {
     fn new(
         method: HttpMethod,
-        req_uri: PathBuf,
-        proto_ver: &str,
-        req_headers: Option<HashMap<String, String>>,
+        req_uri: ReqURI,
+        req_headers: Option<HeaderMap>,
     ) -> HttpRequest {
 
         HttpRequest {
             method,
-            req_uri: ReqURI::new(req_uri.to_str().unwrap().replace(crate::DOC_ROOT, ""), req_uri),
-            proto_ver: String::from(proto_ver),
+            req_uri,
             req_headers: req_headers,
         }
 
@@ -84,7 +150,7 @@ impl HttpRequest {
 
     //TODO: This needs to be refactored to only parse the http request and return the completed struct...valid or not
     //by returning an Err(_) we mask the original request and cannot get anymore information out of it later
-    pub fn parse(request: &str) -> Result<Box<HttpRequest>, HttpStatusCode> {
+    pub fn parse(request: &str, config: &Config) -> Result<Box<HttpRequest>, HttpStatusCode> {
         let raw_headers = request.split("\r\n")
             .filter_map(
                 |v|
@@ -95,13 +161,7 @@ impl HttpRequest {
             .collect::<Vec<&str>>();
         
         
-        let req_headers = raw_headers.clone()
-            .into_iter().map(
-                |s|
-                match s.split_once(' ') {
-                    Some((k,v)) => (String::from(k), String::from(v)),
-                    None => ("none".to_string(), "none".to_string()),
-                }).collect::<HashMap<String, String>>();
+        let req_headers = parse_headers(&raw_headers);
 
         let mut req_vec = match raw_headers.get(0) {
             Some(s) => {
@@ -113,29 +173,38 @@ impl HttpRequest {
 
         //I am pretty sure all http requests have to specify at least the Method, URI, HTTP Protocol so the minimum length
         //for a valid request should be 3
-        if req_vec.len() < 3 {
+        if req_vec.len() != 3 || !is_request_target(req_vec[1]) {
             return Err(HttpStatusCode::BadRequest);
         }
 
-        // should prob check that req_vec has elements?
-        let result = match req_vec[0] {
-            "GET" => HttpRequest::parse_get(&mut req_vec), 
-            "POST" => HttpRequest::parse_post(&mut req_vec),
-            "UPDATE" => HttpRequest::parse_update(&mut req_vec),
-            "DELETE" => HttpRequest::parse_delete(&mut req_vec),
-            "CONNECT" => HttpRequest::parse_connect(&mut req_vec),
-            "TRACE" => HttpRequest::parse_trace(&mut req_vec),
-            "HEAD" => HttpRequest::parse_head(&mut req_vec),
-            "OPTION" => HttpRequest::parse_option(&mut req_vec),
-            _ => Err(HttpStatusCode::BadRequest)
+        if !SUPPORTED_PROTO_VERSIONS.contains(&req_vec[2]) {
+            return Err(HttpStatusCode::HttpVersionNotSupported);
+        }
+
+        let method = match HttpMethod::from_token(req_vec[0]) {
+            Some(m) => m,
+            None => return Err(HttpStatusCode::BadRequest),
+        };
+
+        let result = match method {
+            HttpMethod::GET => HttpRequest::parse_get(&mut req_vec, config), 
+            HttpMethod::HEAD => HttpRequest::parse_head(&mut req_vec, config),
+            HttpMethod::POST => HttpRequest::parse_post(&mut req_vec),
+            HttpMethod::PUT => HttpRequest::parse_put(&mut req_vec),
+            HttpMethod::DELETE => HttpRequest::parse_delete(&mut req_vec),
+            HttpMethod::CONNECT => HttpRequest::parse_connect(&mut req_vec),
+            HttpMethod::OPTIONS => HttpRequest::parse_options(&mut req_vec, config),
+            HttpMethod::TRACE => HttpRequest::parse_trace(&mut req_vec),
+            HttpMethod::PATCH => HttpRequest::parse_patch(&mut req_vec),
+            //a method we have never heard of, RFC 9110 15.6.2
+            HttpMethod::Extension(_) => Err(HttpStatusCode::NotImplemented),
         };
 
         match result {
             Ok(hr) => {
                 return Ok(Box::new(HttpRequest::new(
                     hr.method,
-                    hr.req_uri.file,
-                    &String::from(hr.proto_ver),
+                    hr.req_uri,
                     Some(req_headers)
                 )))
             },
@@ -146,73 +215,262 @@ impl HttpRequest {
 
     }
 
-    fn parse_get(req_vec: &mut Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
-        crate::debug!("GET -> {:?}", &req_vec);
-            //Requesting http://example.com would result in GET / HTTP/1.1
-            //so we rewrite the request to the default index which is index.html -> GET index.html HTTP/1.1
-            if req_vec[1] == "/" {
-                req_vec[1] = crate::DEFAULT_INDEX;
+    /// Case-insensitive header lookup
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.req_headers.as_ref()?.get(name)
+    }
+
+    /// Checks the conditional request headers against the current ETag and mtime of the resource
+    pub fn precondition(&self, etag: &str, modified: std::time::SystemTime) -> Precondition {
+        conditional::evaluate(self, etag, modified)
+    }
+
+    /// The byte ranges to serve out of a representation of `len` bytes
+    ///
+    /// Only GET honours Range, and a stale `If-Range` means the client gets the whole thing instead.
+    pub fn range(&self, len: u64, etag: &str, modified: std::time::SystemTime) -> RangeRequest {
+        if self.method != HttpMethod::GET {
+            return RangeRequest::Full;
+        }
+        let value = match self.header("Range") {
+            Some(v) => v,
+            None => return RangeRequest::Full,
+        };
+        if let Some(if_range) = self.header("If-Range") {
+            if !conditional::if_range_matches(if_range, etag, modified) {
+                return RangeRequest::Full;
             }
+        }
+        range::parse(value, len)
+    }
+
+    fn parse_get(req_vec: &mut Vec<&str>, config: &Config) -> Result<HttpRequest, HttpStatusCode> {
+        crate::debug!("GET -> {:?}", &req_vec);
+        Ok(HttpRequest::new(
+            HttpMethod::GET,
+            HttpRequest::resolve_uri(req_vec[1], config)?,
+            None
+        ))
+    }
+
+    //HEAD has to find exactly what GET would have, only the body is dropped later on
+    fn parse_head(req_vec: &mut Vec<&str>, config: &Config) -> Result<HttpRequest, HttpStatusCode> {
+        crate::debug!("HEAD -> {:?}", &req_vec);
+        Ok(HttpRequest::new(
+            HttpMethod::HEAD,
+            HttpRequest::resolve_uri(req_vec[1], config)?,
+            None
+        ))
+    }
+
+    //Maps the request target onto a file under the doc root
+    fn resolve_uri(target: &str, config: &Config) -> Result<ReqURI, HttpStatusCode> {
+            let uri = target.to_string();
+            let path = request_path(target)?;
 
             //Requesting http://example.com/afile.html would result in GET /afile.html HTTP/1.1
             //we just chop off the / here so when we canonicalize it it doesn't look at the root of the drive
             // ie /afile.html instead of ./afile.html
-            if req_vec[1].starts_with('/') && req_vec[1].len() > 1 {
-                let mut s = req_vec[1];
-                s = &s[1..];
-                req_vec[1] = s;
-            }
+            let relative = path.trim_start_matches('/');
 
             //Attempt to prevent directory recursion exploits hopfully and it has the added bonus
             //of checking if the file exists so we can return a 404
-            let uri_path = PathBuf::from(format!("{}{}", crate::DOC_ROOT,&req_vec[1])).canonicalize();
-            crate::debug!("uri: {:?}", &req_vec[1]);
+            let uri_path = config.server.doc_root.join(relative).canonicalize();
+            crate::debug!("uri: {:?}", relative);
             crate::debug!("PathBuf: {:?}", &uri_path);
             let uri_path = match uri_path {
                 Ok(p) => p,
                 Err(_) => return Err(HttpStatusCode::NotFound),
             };
-            //Check if the (canonical)file is in the allowed doc root path
-            let doc_root_path = PathBuf::from(&crate::DOC_ROOT).canonicalize().unwrap();
+            //Check if the (canonical)file is in the allowed doc root path. The root is resolved per
+            //request so a doc root that is a symlink can be repointed while we run
+            let doc_root_path = match config.server.doc_root.canonicalize() {
+                Ok(p) => p,
+                Err(e) => {
+                    crate::error!("unable to resolve doc root {}: {}", config.server.doc_root.display(), e);
+                    return Err(HttpStatusCode::InternalServerError);
+                },
+            };
             if !uri_path.starts_with(&doc_root_path) {
                 return Err(HttpStatusCode::BadRequest);
             }
 
-            Ok(HttpRequest::new(
-                HttpMethod::GET,
-                uri_path,
-                crate::HTTP_PROTO_VERSION,
-                None
-            ))
+            //Requesting http://example.com would result in GET / HTTP/1.1
+            //so we serve the first of the configured index files that exists in that directory
+            let uri_path = if uri_path.is_dir() {
+                match config.server.index.iter().map(|i| uri_path.join(i)).find(|p| p.is_file()) {
+                    Some(p) => p,
+                    None => return Err(HttpStatusCode::NotFound),
+                }
+            } else {
+                uri_path
+            };
 
+            Ok(ReqURI::new(uri, uri_path))
     }
 
+    //The methods below are ones we know but don't allow on static files, they get a 405 with an Allow header
     fn parse_post(_req_vec: &mut Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
-        Err(HttpStatusCode::NotImplemented)
+        Err(HttpStatusCode::MethodNotAllowed)
     }
 
-    fn parse_update(_req_vec: &mut Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
-        Err(HttpStatusCode::NotImplemented)
+    fn parse_put(_req_vec: &mut Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
+        Err(HttpStatusCode::MethodNotAllowed)
+    }
+
+    fn parse_patch(_req_vec: &mut Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
+        Err(HttpStatusCode::MethodNotAllowed)
     }
 
     fn parse_delete(_req_vec: &mut Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
-        Err(HttpStatusCode::NotImplemented)
+        Err(HttpStatusCode::MethodNotAllowed)
     }
 
     fn parse_connect(_req_vec: &mut Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
-        Err(HttpStatusCode::NotImplemented)
+        Err(HttpStatusCode::MethodNotAllowed)
     }
 
     fn parse_trace(_req_vec: &mut Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
-        Err(HttpStatusCode::NotImplemented)
+        Err(HttpStatusCode::MethodNotAllowed)
+    }
+
+    //OPTIONS * asks about the server as a whole, anything else has to name a resource that exists
+    fn parse_options(req_vec: &mut Vec<&str>, config: &Config) -> Result<HttpRequest, HttpStatusCode> {
+        crate::debug!("OPTIONS -> {:?}", &req_vec);
+        let req_uri = match req_vec[1] {
+            "*" => ReqURI::new("*".to_string(), PathBuf::new()),
+            _ => HttpRequest::resolve_uri(req_vec[1], config)?,
+        };
+
+        Ok(HttpRequest::new(
+            HttpMethod::OPTIONS,
+            req_uri,
+            None
+        ))
+    }
+
+}
+
+//repeated headers are folded into a single comma separated value as allowed by RFC 7230 3.2.2
+fn parse_headers(lines: &[&str]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for line in lines.iter().skip(1) {
+        if let Some((k, v)) = line.split_once(':') {
+            headers.append(k.trim(), v.trim());
+        }
+    }
+    headers
+}
+
+/// Splits a raw request head into its method, request target and headers without resolving the
+/// target against the doc root, None when there is no well formed request line to speak of
+pub fn split_head(head: &str) -> Option<(&str, &str, HeaderMap)> {
+    let lines = head.split("\r\n").collect::<Vec<&str>>();
+    let mut request_line = lines.first()?.split(' ');
+    let method = request_line.next().filter(|m| is_token(m))?;
+    let target = request_line.next().filter(|t| is_request_target(t))?;
+    request_line.next()?;
+    if request_line.next().is_some() {
+        return None;
+    }
+    Some((method, target, parse_headers(&lines)))
+}
+
+/// Splits a request target into the authority an absolute-form target (RFC 9112 3.2.2) starts
+/// with, which names the host in place of the Host header, and the path and query after it
+pub fn split_target(target: &str) -> (Option<&str>, &str) {
+    match target.strip_prefix("http://").or_else(|| target.strip_prefix("https://")) {
+        Some(rest) => match rest.find('/') {
+            Some(i) => (Some(&rest[..i]), &rest[i..]),
+            None => (Some(rest), "/"),
+        },
+        None => (None, target),
     }
+}
 
-    fn parse_head(_req_vec: &mut Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
-        Err(HttpStatusCode::NotImplemented)
+/// The path of a request target with any absolute-form authority and the query dropped and
+/// percent-escapes decoded, a 400 for escapes that aren't two hex digits or that decode to a NUL
+/// or to something not UTF-8
+pub fn request_path(target: &str) -> Result<String, HttpStatusCode> {
+    let (_, target) = split_target(target);
+    let path = target.split('?').next().unwrap_or_default().as_bytes();
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        if path[i] != b'%' {
+            decoded.push(path[i]);
+            i += 1;
+            continue;
+        }
+        let byte = path.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or(HttpStatusCode::BadRequest)?;
+        decoded.push(byte);
+        i += 3;
+    }
+    match String::from_utf8(decoded) {
+        Ok(p) if !p.contains('\0') => Ok(p),
+        _ => Err(HttpStatusCode::BadRequest),
     }
+}
+
+/// Decides from a raw request head whether the connection may be reused after answering it
+///
+/// HTTP/1.1 connections persist unless the client sends `Connection: close`, HTTP/1.0 ones only
+/// when the client asks for `Connection: keep-alive`. We don't read request bodies yet, so any
+/// request that carries one closes the connection rather than leaving the body in the stream.
+pub fn is_persistent(head: &str) -> bool {
+    let lines = head.split("\r\n").collect::<Vec<&str>>();
+    let version = match lines.first().and_then(|l| l.rsplit(' ').next()) {
+        Some(v) => v,
+        None => return false,
+    };
+    let headers = parse_headers(&lines);
 
-    fn parse_option(_req_vec: &mut  Vec<&str>) -> Result<HttpRequest, HttpStatusCode> {
-        Err(HttpStatusCode::NotImplemented)
+    let has_body = headers.get("transfer-encoding").is_some()
+        || headers.get("content-length").is_some_and(|l| l.trim() != "0");
+    if has_body {
+        return false;
     }
 
-}
\ No newline at end of file
+    let connection = headers.get("connection").map(|c| c.to_ascii_lowercase()).unwrap_or_default();
+    let has_token = |t: &str| connection.split(',').any(|c| c.trim() == t);
+    match version {
+        "HTTP/1.1" => !has_token("close"),
+        "HTTP/1.0" => has_token("keep-alive"),
+        _ => false,
+    }
+}
+
+//only visible ASCII, the head reader splits lines on CRLF so a bare LF (or any other control
+//character) would otherwise make it into a Location header and split the response
+fn is_request_target(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_graphic())
+}
+
+//RFC 9110 5.6.2 token characters
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn methods_are_case_sensitive_tokens() {
+        assert_eq!(HttpMethod::from_token("GET"), Some(HttpMethod::GET));
+        assert_eq!(HttpMethod::from_token("OPTIONS"), Some(HttpMethod::OPTIONS));
+        assert_eq!(HttpMethod::from_token("PATCH"), Some(HttpMethod::PATCH));
+        //the standard names only match in upper case, anything else is just another method
+        assert_eq!(HttpMethod::from_token("get"), Some(HttpMethod::Extension("get".to_string())));
+        assert_eq!(HttpMethod::from_token("Head"), Some(HttpMethod::Extension("Head".to_string())));
+        assert_eq!(HttpMethod::from_token("M-SEARCH"), Some(HttpMethod::Extension("M-SEARCH".to_string())));
+        assert_eq!(HttpMethod::from_token("X_1.0~!"), Some(HttpMethod::Extension("X_1.0~!".to_string())));
+
+        for bad in ["", "GE T", "GET:", "(GET)", "G\"ET", "GET\t", "GÉT", "GET\0"] {
+            assert_eq!(HttpMethod::from_token(bad), None, "{:?}", bad);
+        }
+    }
+}
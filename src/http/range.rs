@@ -0,0 +1,300 @@
+use bytes::{Bytes, BytesMut};
+use rand::Rng;
+
+use super::{Body, BodyPart, HttpResponse, HttpStatusCode, Source};
+
+//more ranges than this in one request isn't a media player, we serve the whole thing instead
+static MAX_RANGES: usize = 32;
+
+/// An inclusive byte range that has already been clamped to the representation length
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// No usable Range header, send the full representation with a 200
+    Full,
+    Satisfiable(Vec<ByteRange>),
+    /// 416, none of the ranges overlap the representation
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header (RFC 9110 14.2) against a representation of `len` bytes
+///
+/// Anything we can't make sense of is ignored rather than rejected, the spec lets us answer
+/// with the full representation in that case.
+pub fn parse(value: &str, len: u64) -> RangeRequest {
+    let specs = match value.trim().strip_prefix("bytes=") {
+        Some(s) => s,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let (first, last) = match spec.split_once('-') {
+            Some(p) => p,
+            None => return RangeRequest::Full,
+        };
+
+        let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+            //bytes=a-b
+            (Ok(start), Ok(end)) if start <= end => Some((start, end.min(len.saturating_sub(1)))),
+            (Ok(_), Ok(_)) => return RangeRequest::Full,
+            //bytes=a-
+            (Ok(start), Err(_)) if last.is_empty() => Some((start, len.saturating_sub(1))),
+            //bytes=-n, the last n bytes
+            (Err(_), Ok(suffix)) if first.is_empty() => match suffix {
+                0 => None,
+                n => Some((len.saturating_sub(n), len.saturating_sub(1))),
+            },
+            _ => return RangeRequest::Full,
+        };
+
+        if let Some((start, end)) = range {
+            if start < len {
+                ranges.push(ByteRange { start, end });
+            }
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Satisfiable(coalesce(ranges))
+}
+
+//overlapping or touching ranges are merged so a client can't make us send the same bytes twice
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    if ranges.len() < 2 {
+        return ranges;
+    }
+    ranges.sort_by_key(|r| r.start);
+
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for r in ranges {
+        match merged.last_mut() {
+            Some(prev) if r.start <= prev.end + 1 => prev.end = prev.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+/// Builds the 206, a single range is sent as is and several as multipart/byteranges
+pub fn partial_response(ranges: &[ByteRange], source: Source, content_type: &str) -> HttpResponse {
+    let total = source.len();
+
+    let mut response = if let [range] = ranges {
+        let body = match source {
+            Source::Memory(b) => Body::Bytes(b.slice(range.start as usize..=range.end as usize)),
+            Source::Disk { file, .. } => Body::File {
+                file,
+                parts: vec![BodyPart::File { offset: range.start, len: range.end - range.start + 1 }],
+            },
+        };
+        let mut response = HttpResponse::with_body(HttpStatusCode::PartialContent, body);
+        response.headers.set("Content-Type", content_type);
+        response.headers.set("Content-Range", range.content_range(total));
+        response
+    } else {
+        let boundary = make_boundary();
+        let trailer = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+        let part_header = |r: &ByteRange| Bytes::from(part_header(&boundary, content_type, &r.content_range(total)));
+
+        let body = match source {
+            //small enough to be cached, so just assemble it
+            Source::Memory(b) => {
+                let mut body = BytesMut::new();
+                for range in ranges {
+                    body.extend_from_slice(&part_header(range));
+                    body.extend_from_slice(&b[range.start as usize..=range.end as usize]);
+                }
+                body.extend_from_slice(&trailer);
+                Body::Bytes(body.freeze())
+            },
+            Source::Disk { file, .. } => {
+                let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+                for range in ranges {
+                    parts.push(BodyPart::Bytes(part_header(range)));
+                    parts.push(BodyPart::File { offset: range.start, len: range.end - range.start + 1 });
+                }
+                parts.push(BodyPart::Bytes(trailer));
+                Body::File { file, parts }
+            },
+        };
+        let mut response = HttpResponse::with_body(HttpStatusCode::PartialContent, body);
+        response.headers.set("Content-Type", format!("multipart/byteranges; boundary={}", boundary));
+        response
+    };
+    response.headers.set("Accept-Ranges", "bytes");
+    response
+}
+
+fn part_header(boundary: &str, content_type: &str, content_range: &str) -> String {
+    format!(
+        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+        boundary, content_type, content_range
+    )
+}
+
+fn make_boundary() -> String {
+    format!("{:016x}{:016x}", rand::thread_rng().gen::<u64>(), rand::thread_rng().gen::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn ranges(pairs: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Satisfiable(pairs.iter().map(|&(start, end)| ByteRange { start, end }).collect())
+    }
+
+    #[test]
+    fn single_ranges_are_clamped_to_the_length() {
+        let cases = [
+            ("bytes=0-4", ranges(&[(0, 4)])),
+            ("bytes=5-100", ranges(&[(5, 9)])),
+            ("bytes=3-", ranges(&[(3, 9)])),
+            ("bytes=-4", ranges(&[(6, 9)])),
+            ("bytes=-20", ranges(&[(0, 9)])),
+            (" bytes=9-9 ", ranges(&[(9, 9)])),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse(value, 10), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        for value in ["bytes=10-", "bytes=10-20", "bytes=-0", "bytes=12-15,20-"] {
+            assert_eq!(parse(value, 10), RangeRequest::Unsatisfiable, "{}", value);
+        }
+        //nothing at all can be satisfied out of an empty file
+        for value in ["bytes=0-", "bytes=0-0", "bytes=-5"] {
+            assert_eq!(parse(value, 0), RangeRequest::Unsatisfiable, "{}", value);
+        }
+        //one range that fits is enough
+        assert_eq!(parse("bytes=20-30,0-1", 10), ranges(&[(0, 1)]));
+    }
+
+    #[test]
+    fn malformed_headers_get_the_full_representation() {
+        for value in ["items=0-4", "bytes=4-2", "bytes=abc", "bytes=0-1,junk", "bytes=1", "bytes=--1", "bytes=0-1-2"] {
+            assert_eq!(parse(value, 10), RangeRequest::Full, "{}", value);
+        }
+    }
+
+    #[test]
+    fn overlapping_and_touching_ranges_are_coalesced() {
+        assert_eq!(parse("bytes=0-4,5-9", 10), ranges(&[(0, 9)]));
+        assert_eq!(parse("bytes=0-4,2-6", 10), ranges(&[(0, 6)]));
+        assert_eq!(parse("bytes=6-8,0-2", 10), ranges(&[(0, 2), (6, 8)]));
+        assert_eq!(parse("bytes=0-1,-3,4-5", 10), ranges(&[(0, 1), (4, 5), (7, 9)]));
+        assert_eq!(parse("bytes=2-3,0-9", 10), ranges(&[(0, 9)]));
+    }
+
+    #[test]
+    fn too_many_ranges_get_the_full_representation() {
+        let spec = |n: u64| (0..n).map(|i| format!("{0}-{0}", i * 2)).collect::<Vec<_>>().join(",");
+        match parse(&format!("bytes={}", spec(MAX_RANGES as u64)), 100) {
+            RangeRequest::Satisfiable(r) => assert_eq!(r.len(), MAX_RANGES),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(parse(&format!("bytes={}", spec(MAX_RANGES as u64 + 1)), 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn partial_responses() {
+        let source = || Source::Memory(Bytes::from_static(b"0123456789"));
+
+        let response = partial_response(&[ByteRange { start: 2, end: 4 }], source(), "text/plain");
+        assert_eq!(response.status, HttpStatusCode::PartialContent);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain"));
+        match &response.body {
+            Body::Bytes(b) => assert_eq!(&b[..], b"234"),
+            other => panic!("{:?}", other),
+        }
+
+        let both = [ByteRange { start: 0, end: 1 }, ByteRange { start: 8, end: 9 }];
+        let response = partial_response(&both, source(), "text/plain");
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let expected = format!(
+            "\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{0}--\r\n",
+            boundary
+        );
+        match &response.body {
+            Body::Bytes(b) => assert_eq!(std::str::from_utf8(b).unwrap(), expected),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    //a file over the cache limit, which partial responses only ever point into
+    fn disk_source(contents: &[u8]) -> Source {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        Source::Disk { file, len: contents.len() as u64 }
+    }
+
+    fn file_spans(body: &Body) -> Vec<(u64, u64)> {
+        match body {
+            Body::File { parts, .. } => parts
+                .iter()
+                .filter_map(|p| match p {
+                    BodyPart::File { offset, len } => Some((*offset, *len)),
+                    BodyPart::Bytes(_) => None,
+                })
+                .collect(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    async fn written(body: Body) -> Vec<u8> {
+        let mut out = Vec::new();
+        body.write_to(&mut out).await.unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn partial_responses_from_disk() {
+        let contents = b"0123456789";
+
+        let response = partial_response(&[ByteRange { start: 2, end: 4 }], disk_source(contents), "text/plain");
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(file_spans(&response.body), [(2, 3)]);
+        assert_eq!(written(response.body).await, b"234");
+
+        let three = [ByteRange { start: 0, end: 1 }, ByteRange { start: 5, end: 5 }, ByteRange { start: 8, end: 9 }];
+        let response = partial_response(&three, disk_source(contents), "text/plain");
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap().to_string();
+        assert_eq!(file_spans(&response.body), [(0, 2), (5, 1), (8, 2)]);
+        let expected = format!(
+            "\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 5-5/10\r\n\r\n5\
+             \r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{0}--\r\n",
+            boundary
+        );
+        //the Content-Length sent up front has to match what the parts write out
+        assert_eq!(response.body.len(), expected.len() as u64);
+        assert_eq!(String::from_utf8(written(response.body).await).unwrap(), expected);
+    }
+}
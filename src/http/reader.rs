@@ -0,0 +1,156 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+static HEAD_TERMINATOR: &[u8] = b"\r\n\r\n";
+static READ_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// The peer closed the connection before sending anything
+    Closed,
+    /// The peer closed the connection part way through a request head
+    Incomplete,
+    /// The request head grew past the configured maximum without being terminated
+    TooLarge,
+    Io(std::io::Error),
+}
+
+/// Accumulates bytes off a connection until a complete request head (`\r\n\r\n`) has arrived
+///
+/// Anything read past the end of the head is kept in the buffer for the next call, so the same
+/// reader should be used for the whole life of a connection.
+pub struct RequestReader {
+    buf: Vec<u8>,
+    max_header_size: usize,
+}
+
+impl RequestReader {
+    pub fn new(max_header_size: usize) -> RequestReader {
+        RequestReader {
+            buf: Vec::with_capacity(READ_CHUNK_SIZE),
+            max_header_size,
+        }
+    }
+
+    pub async fn read_head<R: AsyncRead + Unpin>(&mut self, stream: &mut R) -> Result<String, ReadError> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        //where to resume looking for the terminator, so we don't rescan the whole buffer on every read
+        let mut scanned: usize = 0;
+
+        loop {
+            //RFC 7230 3.5: a server SHOULD ignore at least one empty line received prior to the request-line
+            while self.buf.starts_with(b"\r\n") {
+                self.buf.drain(..2);
+                scanned = 0;
+            }
+
+            let search_from = scanned.saturating_sub(HEAD_TERMINATOR.len() - 1);
+            if let Some(pos) = find(&self.buf[search_from..], HEAD_TERMINATOR) {
+                let end = search_from + pos;
+                if end > self.max_header_size {
+                    return Err(ReadError::TooLarge);
+                }
+
+                let head = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+                self.buf.drain(..end + HEAD_TERMINATOR.len());
+                return Ok(head);
+            }
+            scanned = self.buf.len();
+
+            if self.buf.len() > self.max_header_size {
+                return Err(ReadError::TooLarge);
+            }
+
+            match stream.read(&mut chunk).await {
+                Ok(0) if self.buf.is_empty() => return Err(ReadError::Closed),
+                Ok(0) => return Err(ReadError::Incomplete),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) => return Err(ReadError::Io(e)),
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+
+    //hands out one chunk per read, the way a peer's writes arrive in separate segments
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for Chunks {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            if let Some(chunk) = self.0.pop_front() {
+                buf.put_slice(&chunk);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn chunks(chunks: &[&[u8]]) -> Chunks {
+        Chunks(chunks.iter().map(|c| c.to_vec()).collect())
+    }
+
+    #[tokio::test]
+    async fn head_split_across_reads() {
+        let mut stream = chunks(&[b"GET / HT", b"TP/1.1\r\nHost: a\r", b"\n", b"\r", b"\n"]);
+        let mut reader = RequestReader::new(1024);
+        assert_eq!(reader.read_head(&mut stream).await.unwrap(), "GET / HTTP/1.1\r\nHost: a");
+        assert!(matches!(reader.read_head(&mut stream).await, Err(ReadError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_in_one_read() {
+        let mut stream = chunks(&[b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nHost: b\r\n\r\nGET /c", b" HTTP/1.1\r\n\r\n"]);
+        let mut reader = RequestReader::new(1024);
+        assert_eq!(reader.read_head(&mut stream).await.unwrap(), "GET /a HTTP/1.1");
+        //the rest of the first read is the start of the next requests, not something to drop
+        assert_eq!(reader.read_head(&mut stream).await.unwrap(), "GET /b HTTP/1.1\r\nHost: b");
+        assert_eq!(reader.read_head(&mut stream).await.unwrap(), "GET /c HTTP/1.1");
+        assert!(matches!(reader.read_head(&mut stream).await, Err(ReadError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn empty_lines_before_the_request_line_are_skipped() {
+        let mut stream = chunks(&[b"GET /a HTTP/1.1\r\n\r\n\r\n", b"\r\nGET /b HTTP/1.1\r\n\r\n"]);
+        let mut reader = RequestReader::new(1024);
+        assert_eq!(reader.read_head(&mut stream).await.unwrap(), "GET /a HTTP/1.1");
+        assert_eq!(reader.read_head(&mut stream).await.unwrap(), "GET /b HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn heads_over_max_header_size_are_too_large() {
+        let head = format!("GET / HTTP/1.1\r\nX-Filler: {}\r\n\r\n", "a".repeat(100)).into_bytes();
+        //terminated in the same read
+        let mut reader = RequestReader::new(64);
+        assert!(matches!(reader.read_head(&mut chunks(&[&head])).await, Err(ReadError::TooLarge)));
+        //never terminated, the reader gives up instead of buffering without end
+        let mut endless = Chunks((0..1000).map(|_| b"X-Filler: aaaaaaaa\r\n".to_vec()).collect());
+        let mut reader = RequestReader::new(1024);
+        assert!(matches!(reader.read_head(&mut endless).await, Err(ReadError::TooLarge)));
+        assert!(endless.0.len() > 900);
+        //right at the limit is fine
+        let mut reader = RequestReader::new(head.len() - 4);
+        assert!(reader.read_head(&mut chunks(&[&head])).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn eof_mid_head_is_incomplete() {
+        let mut reader = RequestReader::new(1024);
+        let result = reader.read_head(&mut chunks(&[b"GET / HTTP/1.1\r\n", b"Host: a\r\n"])).await;
+        assert!(matches!(result, Err(ReadError::Incomplete)));
+
+        let mut reader = RequestReader::new(1024);
+        assert!(matches!(reader.read_head(&mut chunks(&[])).await, Err(ReadError::Closed)));
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::{Body, HeaderMap, HttpStatusCode};
+
+static SERVER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+//headers the serializer owns, anything set on the response with these names is replaced
+static FRAMING_HEADERS: [&str; 3] = ["Content-Length", "Date", "Server"];
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: HttpStatusCode,
+    pub headers: HeaderMap,
+    pub body: Body,
+    //false for answers to HEAD, the head still describes the body that GET would have sent
+    send_body: bool,
+}
+
+impl HttpResponse {
+    pub fn new(status: HttpStatusCode) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: Body::Bytes(Bytes::new()),
+            send_body: true,
+        }
+    }
+
+    pub fn with_body<B: Into<Body>>(status: HttpStatusCode, body: B) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: body.into(),
+            send_body: true,
+        }
+    }
+
+    /// Keeps every header, `Content-Length` included, but sends nothing after them
+    pub fn without_body(mut self) -> HttpResponse {
+        self.send_body = false;
+        self
+    }
+
+    /// Every header field to send, in order
+    ///
+    /// `Date`, `Server` and `Content-Length` always come from here so the framing can't disagree with the body.
+    pub fn fields(&self) -> Vec<(&str, String)> {
+        let mut fields = vec![
+            ("Date", httpdate::fmt_http_date(SystemTime::now())),
+            ("Server", SERVER_NAME.to_string()),
+        ];
+        if allows_body(self.status.value().0) {
+            fields.push(("Content-Length", self.body.len().to_string()));
+        }
+        fields.extend(self.headers.iter()
+            .filter(|(k, _)| !FRAMING_HEADERS.iter().any(|f| f.eq_ignore_ascii_case(k)))
+            .map(|(k, v)| (k, v.to_string())));
+        fields
+    }
+
+    /// Renders the status line and headers ready to be written to the socket ahead of the body
+    pub fn serialize_head(&self) -> Vec<u8> {
+        let (code, reason) = self.status.value();
+        let mut head = format!("{} {} {}\r\n", crate::HTTP_PROTO_VERSION, code, reason);
+        for (k, v) in self.fields() {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+
+        head.into_bytes()
+    }
+
+    /// Whether anything goes out after the head
+    pub fn sends_body(&self) -> bool {
+        self.send_body && allows_body(self.status.value().0)
+    }
+
+    /// Writes the whole response, cached bodies go out without being copied and files are streamed
+    pub async fn write_to<W: AsyncWrite + Unpin>(self, w: &mut W) -> Result<(), std::io::Error> {
+        w.write_all(&self.serialize_head()).await?;
+        if self.sends_body() {
+            self.body.write_to(w).await?;
+        }
+        Ok(())
+    }
+}
+
+//RFC 7230 3.3.2/3.3.3: 1xx, 204 and 304 responses never carry a body, and we leave Content-Length off them too
+fn allows_body(code: u16) -> bool {
+    code >= 200 && code != 204 && code != 304
+}
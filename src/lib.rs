@@ -1,118 +1,95 @@
+mod acme;
+pub mod config;
 mod http;
 mod filestore;
+mod server;
+mod tls;
 
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
 
-use std::borrow::BorrowMut;
+use config::Config;
 
-use http::{HttpMethod, HttpRequest, HttpStatusCode};
-use filestore::{FileCache};
+pub use server::{Reloader, Server, ServerBuilder, ServerHandle, Shutdown};
 
 use log::*;
 
-use lazy_static::lazy_static;
-
-static BIND_ADDR: &str = "127.0.0.1:8080";
-static DOC_ROOT: &str = "./html/";
-static DEFAULT_INDEX: &str = "index.html";
-static _NOTFOUND_PAGE: &str = "404.html";
-
 //This project currently is referencing RFC 2616 for the implementation of HTTP/1.1, I wouldn't change this...
 static HTTP_PROTO_VERSION: &str = "HTTP/1.1";
 
-lazy_static! {
-    static ref FILECACHE: FileCache = FileCache::new(&DOC_ROOT);
+/// Serves `config` on a runtime of its own until SIGINT or SIGTERM, for embedding use `Server`
+///
+/// With a `config_path` SIGHUP re-reads it, `log_level` from the file is applied as well unless
+/// `pinned_log_level` says the command line already chose one.
+pub fn run(config: Config, config_path: Option<PathBuf>, pinned_log_level: bool) -> Result<Shutdown, Box<dyn std::error::Error>> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let handle = Server::builder(config).build()?.start().await?;
+        debug!("server started on {:?}", handle.local_addrs());
+
+        if let Some(path) = config_path {
+            tokio::spawn(reload_on_hangup(handle.reloader(), path, pinned_log_level));
+        }
+        Ok(handle.shutdown_on(termination()).await?)
+    })
 }
 
-#[tokio::main]
-pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(BIND_ADDR).await?;
-    info!("Listening on {}", &BIND_ADDR);
-
-    lazy_static::initialize(&FILECACHE);
-
-    loop {
-        let boxed_result= Box::new(listener.accept().await?);
-        
-        tokio::spawn(async move { 
-            handle_connection(boxed_result).await;
-        });
+//a bad file is logged and otherwise ignored, the server carries on with what it had
+async fn reload_on_hangup(reloader: Reloader, path: PathBuf, pinned_log_level: bool) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("unable to listen for SIGHUP, config reloads are disabled: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading {}", path.display());
+            let reloaded = Config::load(&path).and_then(|config| {
+                let level = config.log_level;
+                reloader.reload(config).map(|_| level)
+            });
+            match reloaded {
+                Ok(Some(level)) if !pinned_log_level => {
+                    log::set_max_level(level);
+                    info!("Logging level set to {}", level);
+                },
+                Ok(_) => {},
+                Err(e) => error!("keeping the running config, {}", e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (reloader, path, pinned_log_level);
     }
 }
 
-async fn handle_connection(mut boxed_result: Box<(TcpStream, std::net::SocketAddr)>) {
-    let mut buf: [u8; 1024] = [0; 1024];
-    let (stream, addr) = boxed_result.borrow_mut();
-    info!("New client connection from {}", addr);
-
-    let bytes_read = stream.read(&mut buf).await;
-    let _bytes_read = match bytes_read {
-        Ok(b) => {
-            debug!("received {} bytes from {}", &b, &addr);
-            b
-        },
-        Err(e) => {
-            debug!("received an error on bytes read: {} from {}", e, &addr);
-            return;
-        }
-    };
-    
-
-    let request = tokio::task::block_in_place(|| {
-            let buf = String::from_utf8_lossy(&buf);
-            return HttpRequest::parse(&buf);
-    });
-
-    match request {
-        Ok(req) => match req.method {
-            HttpMethod::GET => {
-                debug!(
-                    "GET request from {} -> \n{:#?}",
-                    stream.peer_addr().unwrap(),
-                    &req
-                );
-
-                let index_page = tokio::task::block_in_place(|| {
-                    FILECACHE.open(&req.req_uri.file.to_str().unwrap()); 
-                    return FILECACHE.read(&req.req_uri.file.to_str().unwrap()); 
-                });
-                
-
-                let response = format!(
-                    "{} {} {}\r\n\r\n{}",
-                    HTTP_PROTO_VERSION,
-                    HttpStatusCode::HttpOk.value().0,
-                    HttpStatusCode::HttpOk.value().1,
-                    &index_page
-                );
-                stream.write_all(response.as_bytes()).await.unwrap();
-                stream.flush().await.unwrap();
+/// Resolves on the first SIGINT (Ctrl-C) or SIGTERM
+pub async fn termination() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("unable to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
             }
-            HttpMethod::POST => {}
-            HttpMethod::DELETE => {}
-            HttpMethod::UPDATE => {}
-            HttpMethod::HEAD => {}
-            HttpMethod::OPTION => {}
-            HttpMethod::CONNECT => {}
-            HttpMethod::TRACE => {}
-        },
-        Err(ref e) => {
-            let response = format!(
-                "{} {} {}\r\n\r\n",
-                HTTP_PROTO_VERSION,
-                e.value().0,
-                e.value().1
-            );
-            stream.write_all(response.as_bytes()).await.unwrap();
-            stream.flush().await.unwrap();
-            debug!(
-                "received {:?}  from {} -> {:?}",
-                e,
-                stream.peer_addr().unwrap().ip(),
-                e
-            );
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+            _ = sigterm.recv() => info!("Received SIGTERM"),
         }
     }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Received Ctrl-C");
+    }
 }
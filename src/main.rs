@@ -15,10 +15,13 @@ extern crate rand;
 extern crate notify;
 
 use std::env;
+use std::path::PathBuf;
 
 use clap::{App, Arg};
+use log::LevelFilter;
 
-static LOG_KEY: &str = "RUST_LOG";
+use webserv::config::Config;
+use webserv::Shutdown;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
@@ -41,35 +44,62 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .get_matches();
 
-    if let Some(c) = matches.value_of("config") {
-        eprintln!("Value for config: {}", c);
-    }
+    let config_path = matches.value_of("config").map(PathBuf::from);
+    let config = match &config_path {
+        Some(c) => match Config::load(c) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Config::default(),
+    };
 
     // You can see how many times a particular flag or argument occurred
     // Note, only flags can have multiple occurrences
-    match matches.occurrences_of("verbose") {
-        0 => {
+    let level = match (matches.occurrences_of("verbose"), config.log_level) {
+        (0, Some(level)) => {
+            eprintln!("Logging level set to {} (from config)", level);
+            level
+        },
+        (0, None) => {
             eprintln!("Logging level set to 0 (error)");
-            std::env::set_var(LOG_KEY, "ERROR");
+            LevelFilter::Error
         },
-        1 => {
+        (1, _) => {
             eprintln!("Logging level is 1 (info, error)");
-            std::env::set_var(LOG_KEY, "WARN");
+            LevelFilter::Warn
         },
-        2 => { 
+        (2, _) => { 
             eprintln!("Logging level is 2 (info, warn, error)");
-            std::env::set_var(LOG_KEY, "INFO");
+            LevelFilter::Info
         },
-        3  => {
+        (3, _) => {
             eprintln!("Logging level is 3 (info, warn, error, debug)");
-            std::env::set_var(LOG_KEY, "DEBUG");
+            LevelFilter::Debug
         },
-        4 | _ => {
+        _ => {
             eprintln!("Logging level is 4 (info, warn, error, debug)");
-            std::env::set_var(LOG_KEY, "TRACE");
+            LevelFilter::Trace
         },
-    }
-    env_logger::init();
+    };
+    //the logger itself lets everything through and the level is kept with log's max level
+    //instead, so a config reload can raise it as well as lower it
+    env_logger::Builder::new().filter_level(LevelFilter::Trace).init();
+    log::set_max_level(level);
 
-    webserv::run()
+    //0 for a clean drain, 2 when connections had to be cut, 1 when the server itself failed
+    let pinned_log_level = matches.occurrences_of("verbose") > 0;
+    match webserv::run(config, config_path, pinned_log_level) {
+        Ok(Shutdown::Drained) => Ok(()),
+        Ok(Shutdown::Aborted { connections }) => {
+            eprintln!("shut down with {} connections cut short", connections);
+            std::process::exit(2);
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
 }
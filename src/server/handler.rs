@@ -0,0 +1,224 @@
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use log::*;
+
+use crate::config::ClientAuthConfig;
+use crate::http::{self, HttpMethod, HttpRequest, HttpResponse, HttpStatusCode, Precondition, RangeRequest};
+use crate::tls::ClientCert;
+use super::vhost::Site;
+use super::{redirect, Peer, ServerState};
+
+/// Turns a request head (or the error reading it) into the response to send, runs on the blocking
+/// pool since the file cache and path resolution hit the disk
+///
+/// ACME challenges and HTTPS redirects are answered for every host alike, anything else is served
+/// from the site the request's Host picks.
+pub(crate) fn respond(head: Result<String, HttpStatusCode>, peer: &Peer, state: &ServerState) -> HttpResponse {
+    //HTTP-01 validation always comes in over plain HTTP, ahead of any redirect
+    let challenge = head.as_ref().ok()
+        .filter(|_| !peer.secure)
+        .and_then(|h| http::split_head(h))
+        .and_then(|(_, target, _)| state.challenges.answer(target));
+    if let Some(key_authorization) = challenge {
+        let mut response = HttpResponse::with_body(HttpStatusCode::HttpOk, Bytes::from(key_authorization));
+        response.headers.set("Content-Type", "application/octet-stream");
+        return response;
+    }
+
+    let redirect_config = state.config.tls.as_ref().and_then(|t| t.redirect.as_ref());
+    if let (Ok(head), Some(port), Some(config)) = (&head, peer.redirect_port, redirect_config) {
+        match redirect::redirect(head, port, config) {
+            Some(Ok(response)) => return response,
+            Some(Err(e)) => return error_response(e, state.sites.fallback()),
+            None => (),
+        }
+    }
+
+    let site = match head.as_deref().map(|h| state.sites.select(h)) {
+        Ok(Ok(site)) => site,
+        Ok(Err(e)) => return error_response(e, state.sites.fallback()),
+        Err(_) => state.sites.fallback(),
+    };
+    let request = head.and_then(|head| {
+        check_target(&head, &peer.client_cert, site)?;
+        HttpRequest::parse(&head, &site.config)
+    });
+    let mut response = match request {
+        Ok(req) => {
+            debug!("{:?} request -> \n{:#?}", &req.method, &req);
+            match check_resolved(&req, &peer.client_cert, site) {
+                Ok(()) => handle_request(&req, site),
+                Err(reason) => {
+                    info!("refused {:?} {} to client {}", &req.method, &req.req_uri.uri, reason);
+                    error_response(HttpStatusCode::Forbidden, site)
+                },
+            }
+        },
+        Err(e) => {
+            debug!("answering with {:?}", e);
+            //errors for requests we did understand (a 404 for one asset on a page) don't need to cost the connection
+            error_response(e, site)
+        }
+    };
+    for (name, value) in &site.headers {
+        response.headers.set(name, value.as_str());
+    }
+    response
+}
+
+//the path as requested (with `.` and `..` applied) is checked against tls.client_auth.paths before
+//it is resolved, so a missing file under a protected prefix gets the same 403 as an existing one
+//rather than telling a client without a certificate which files exist
+fn check_target(head: &str, client_cert: &ClientCert, site: &Site) -> Result<(), HttpStatusCode> {
+    let (method, target) = match http::split_head(head) {
+        Some((method, target, _)) => (method, target),
+        None => return Ok(()),
+    };
+    //the parser turns away what doesn't decode
+    let path = match http::request_path(target) {
+        Ok(p) => p,
+        Err(_) => return Ok(()),
+    };
+    let mut relative = PathBuf::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                relative.pop();
+            },
+            s => relative.push(s),
+        }
+    }
+    check_client_cert(&relative, client_cert, site).map_err(|reason| {
+        info!("refused {} {} to client {}", method, target, reason);
+        HttpStatusCode::Forbidden
+    })
+}
+
+//and the resolved file once more, so symlinks into a protected directory can't get around a prefix
+fn check_resolved(req: &HttpRequest, client_cert: &ClientCert, site: &Site) -> Result<(), String> {
+    if client_auth(site).is_none() {
+        return Ok(());
+    }
+    let relative = match site.config.server.doc_root.canonicalize() {
+        Ok(root) => req.req_uri.file.strip_prefix(root).map(|p| p.to_path_buf()).unwrap_or_default(),
+        Err(_) => return Err("doc root unavailable".to_string()),
+    };
+    check_client_cert(&relative, client_cert, site)
+}
+
+fn check_client_cert(relative: &Path, client_cert: &ClientCert, site: &Site) -> Result<(), String> {
+    let auth = match client_auth(site) {
+        Some(a) => a,
+        None => return Ok(()),
+    };
+    let protected = auth.paths.iter().any(|p| relative.starts_with(p.trim_start_matches('/')));
+    if !protected {
+        return Ok(());
+    }
+
+    match client_cert {
+        ClientCert::Verified(id) if id.allowed_by(&auth.allow) => Ok(()),
+        ClientCert::Verified(id) => Err(format!("{} not in tls.client_auth.allow", id)),
+        ClientCert::Rejected(e) => Err(format!("with a rejected certificate ({})", e)),
+        ClientCert::None => Err("without a certificate".to_string()),
+    }
+}
+
+fn client_auth(site: &Site) -> Option<&ClientAuthConfig> {
+    site.config.tls.as_ref().and_then(|t| t.client_auth.as_ref())
+}
+
+fn handle_request(req: &HttpRequest, site: &Site) -> HttpResponse {
+    match req.method {
+        HttpMethod::GET | HttpMethod::HEAD => {
+            let file = match site.filecache.fetch(req.req_uri.file.to_str().unwrap()) {
+                Ok(f) => f,
+                //it existed when the request was parsed, so it was removed or became unreadable since
+                Err(e) => {
+                    debug!("unable to open {:?}: {}", &req.req_uri.file, e);
+                    return error_response(HttpStatusCode::NotFound, site);
+                }
+            };
+
+            let mut response = match req.precondition(&file.etag, file.modified) {
+                Precondition::Proceed => {
+                    let len = file.contents.len();
+                    match req.range(len, &file.etag, file.modified) {
+                        RangeRequest::Full => {
+                            let mut response = HttpResponse::with_body(HttpStatusCode::HttpOk, file.contents.into_body());
+                            response.headers.set("Content-Type", &*file.content_type);
+                            response.headers.set("Accept-Ranges", "bytes");
+                            response
+                        },
+                        RangeRequest::Satisfiable(ranges) => http::partial_response(&ranges, file.contents, &file.content_type),
+                        RangeRequest::Unsatisfiable => {
+                            let mut response = error_response(HttpStatusCode::RangeNotSatisfiable, site);
+                            response.headers.set("Content-Range", format!("bytes */{}", len));
+                            return response;
+                        },
+                    }
+                },
+                Precondition::NotModified => HttpResponse::new(HttpStatusCode::NotModified),
+                Precondition::Failed => return error_response(HttpStatusCode::PreconditionFailed, site),
+            };
+            response.headers.set("ETag", &*file.etag);
+            response.headers.set("Last-Modified", httpdate::fmt_http_date(file.modified));
+            response
+        },
+        HttpMethod::OPTIONS => {
+            let mut response = HttpResponse::new(HttpStatusCode::NoContent);
+            response.headers.set("Allow", http::ALLOWED_METHODS);
+            response
+        },
+        //parse() only hands us methods it knows how to serve
+        HttpMethod::POST
+        | HttpMethod::PUT
+        | HttpMethod::PATCH
+        | HttpMethod::DELETE
+        | HttpMethod::CONNECT
+        | HttpMethod::TRACE => error_response(HttpStatusCode::MethodNotAllowed, site),
+        HttpMethod::Extension(_) => error_response(HttpStatusCode::NotImplemented, site),
+    }
+}
+
+/// Builds the response for an error status, using the page configured in `[error_pages]` when it
+/// exists under the doc root and a small built-in page otherwise
+fn error_response(status: HttpStatusCode, site: &Site) -> HttpResponse {
+    let mut response = error_page(status, site);
+    //RFC 9110 15.5.6: a 405 has to say what would have worked, we do the same for 501
+    if status == HttpStatusCode::MethodNotAllowed || status == HttpStatusCode::NotImplemented {
+        response.headers.set("Allow", http::ALLOWED_METHODS);
+    }
+    response
+}
+
+fn error_page(status: HttpStatusCode, site: &Site) -> HttpResponse {
+    let config = &site.config;
+    let page = config.error_pages.get(&status.value().0)
+        .and_then(|p| config.server.doc_root.join(p).canonicalize().ok())
+        .filter(|p| p.is_file())
+        .filter(|p| match config.server.doc_root.canonicalize() {
+            Ok(root) => p.starts_with(root),
+            Err(_) => false,
+        });
+
+    match page.map(|p| site.filecache.fetch(p.to_str().unwrap())) {
+        Some(Ok(file)) => {
+            let mut response = HttpResponse::with_body(status, file.contents.into_body());
+            response.headers.set("Content-Type", &*file.content_type);
+            response
+        },
+        _ => {
+            let (code, reason) = status.value();
+            let body = format!(
+                "<!DOCTYPE html>\n<html lang=\"en\">\n  <head>\n    <meta charset=\"utf-8\">\n    <title>{0} {1}</title>\n  </head>\n  <body>\n    <h1>{0} {1}</h1>\n  </body>\n</html>\n",
+                code, reason
+            );
+            let mut response = HttpResponse::with_body(status, Bytes::from(body));
+            response.headers.set("Content-Type", "text/html; charset=utf-8");
+            response
+        }
+    }
+}
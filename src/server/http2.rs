@@ -0,0 +1,261 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use log::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep_until, timeout, Instant};
+
+use super::{add_common_headers, handler, log_connection, until, Peer, Phase, ServerState};
+use crate::http::HttpResponse;
+
+/// What a client speaking HTTP/2 with prior knowledge opens with, RFC 9113 3.4
+static PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+//only mean something to an HTTP/1.1 connection, RFC 9113 8.2.2 and RFC 9114 4.2 make them malformed
+static CONNECTION_HEADERS: [&str; 5] = ["Connection", "Keep-Alive", "Proxy-Connection", "Transfer-Encoding", "Upgrade"];
+
+/// Reads just enough of a plain connection to tell the HTTP/2 preface from an HTTP/1.x request,
+/// true when it is the preface. Whatever was read is handed back in front of the stream.
+pub(super) async fn sniff<S: AsyncRead + Unpin>(mut stream: S, wait: Duration) -> Result<(Rewind<S>, bool), io::Error> {
+    let mut read = Vec::with_capacity(PREFACE.len());
+    let mut buf = [0u8; 24];
+    while read.len() < PREFACE.len() && PREFACE.starts_with(&read) {
+        //an idle or closed connection is left to the HTTP/1.x side to deal with
+        match timeout(wait, stream.read(&mut buf[..PREFACE.len() - read.len()])).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => read.extend_from_slice(&buf[..n]),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+    let is_h2 = read == PREFACE;
+    Ok((Rewind { prefix: Bytes::from(read), inner: stream }, is_h2))
+}
+
+/// Serves an HTTP/2 connection, every stream is answered on a task of its own through the same
+/// handler HTTP/1.x requests go through
+///
+/// `http2.max_concurrent_streams` is advertised to the client and streams past it are refused.
+/// The connection is closed with a GOAWAY once it has been idle for `http.keep_alive_timeout` and
+/// on shutdown, after the streams already open have been answered.
+pub(super) async fn serve<S>(stream: S, addr: SocketAddr, peer: Peer, state: Arc<ServerState>, mut phase: watch::Receiver<Phase>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    log_connection(&addr, &peer, "HTTP/2");
+    let peer = Arc::new(peer);
+    let config = &state.config.http2;
+    let idle_timeout = Duration::from_secs(state.config.http.keep_alive_timeout);
+
+    let mut builder = h2::server::Builder::new();
+    builder.max_concurrent_streams(config.max_concurrent_streams)
+        .initial_window_size(config.initial_window_size)
+        .initial_connection_window_size(config.connection_window_size)
+        .max_frame_size(config.max_frame_size)
+        .max_header_list_size(state.config.http.max_header_size as u32);
+    let mut connection = match timeout(idle_timeout, builder.handshake::<_, Bytes>(stream)).await {
+        Ok(Ok(c)) => c,
+        Ok(Err(e)) => {
+            debug!("HTTP/2 handshake with {} failed: {}", &addr, e);
+            return;
+        },
+        Err(_) => {
+            debug!("HTTP/2 handshake with {} timed out", &addr);
+            return;
+        },
+    };
+
+    //stream tasks report back here when they are done, so we know when the connection goes idle
+    let (done, mut finished) = mpsc::unbounded_channel();
+    let mut open = 0;
+    let mut served = 0;
+    let mut idle_since = Instant::now();
+    let mut closing = false;
+    let mut abort = phase.clone();
+
+    loop {
+        tokio::select! {
+            accepted = connection.accept() => match accepted {
+                Some(Ok((request, respond))) => {
+                    open += 1;
+                    served += 1;
+                    let (peer, state, done) = (Arc::clone(&peer), Arc::clone(&state), done.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_stream(request, respond, peer, state).await {
+                            debug!("error answering HTTP/2 stream from {}: {}", &addr, e);
+                        }
+                        let _ = done.send(());
+                    });
+                },
+                Some(Err(e)) => {
+                    debug!("HTTP/2 connection from {} failed: {}", &addr, e);
+                    return;
+                },
+                //the client hung up, or every stream was answered after our GOAWAY
+                None => break,
+            },
+            Some(()) = finished.recv() => {
+                open -= 1;
+                if open == 0 {
+                    idle_since = Instant::now();
+                }
+            },
+            _ = sleep_until(idle_since + idle_timeout), if open == 0 && !closing => {
+                debug!("closing idle HTTP/2 connection from {} after {} streams", &addr, served);
+                connection.graceful_shutdown();
+                closing = true;
+            },
+            _ = until(&mut phase, |p| *p != Phase::Running), if !closing => {
+                debug!("closing HTTP/2 connection from {} for shutdown after {} streams", &addr, served);
+                connection.graceful_shutdown();
+                closing = true;
+            },
+            _ = until(&mut abort, |p| *p == Phase::Aborting) => {
+                debug!("cut HTTP/2 connection to {} at the end of the shutdown grace period", &addr);
+                return;
+            },
+        }
+    }
+    debug!("HTTP/2 connection from {} closed after {} streams", &addr, served);
+}
+
+async fn serve_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    peer: Arc<Peer>,
+    state: Arc<ServerState>,
+) -> Result<(), io::Error> {
+    //we don't take request bodies, dropping it tells the client to stop sending
+    let (parts, _) = request.into_parts();
+    let response = answer(&parts, peer, state).await?;
+
+    let sends_body = response.sends_body();
+    let stream = respond.send_response(response_head(&response)?, !sends_body).map_err(io::Error::other)?;
+    if sends_body {
+        let mut body = H2Body(stream);
+        response.body.write_to(&mut body).await?;
+        body.0.send_data(Bytes::new(), true).map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+/// Answers an HTTP/2 or HTTP/3 request the way the same request over HTTP/1.1 would be
+pub(super) async fn answer(parts: &http::request::Parts, peer: Arc<Peer>, state: Arc<ServerState>) -> Result<HttpResponse, io::Error> {
+    let head = request_head(parts);
+    let blocking_state = Arc::clone(&state);
+    let blocking_peer = Arc::clone(&peer);
+    let mut response = tokio::task::spawn_blocking(move || handler::respond(Ok(head), &blocking_peer, &blocking_state))
+        .await
+        .map_err(io::Error::other)?;
+    if parts.method == http::Method::HEAD {
+        response = response.without_body();
+    }
+    add_common_headers(&mut response, &state, &peer);
+    Ok(response)
+}
+
+//the request as the HTTP/1.1 head the handler parses, :authority standing in for Host
+fn request_head(parts: &http::request::Parts) -> String {
+    let target = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut head = format!("{} {} {}\r\n", parts.method, target, crate::HTTP_PROTO_VERSION);
+    let authority = parts.uri.authority();
+    if let Some(a) = authority {
+        head.push_str(&format!("Host: {}\r\n", a));
+    }
+    for (name, value) in &parts.headers {
+        if authority.is_some() && name == http::header::HOST {
+            continue;
+        }
+        //the handler only reads ASCII headers, anything else couldn't have come in over HTTP/1.1 either
+        if let Ok(value) = value.to_str() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str("\r\n");
+    head
+}
+
+/// The status and header fields of `response` without the ones only HTTP/1.1 has a use for
+pub(super) fn response_head(response: &HttpResponse) -> Result<http::Response<()>, io::Error> {
+    let mut head = http::Response::builder().status(response.status.value().0);
+    for (name, value) in response.fields() {
+        if !CONNECTION_HEADERS.iter().any(|c| c.eq_ignore_ascii_case(name)) {
+            head = head.header(name, value);
+        }
+    }
+    head.body(()).map_err(io::Error::other)
+}
+
+//a stream's DATA frames as an AsyncWrite, every write waits for room in the client's flow
+//control window so a slow reader holds back our file reads like it does over HTTP/1.1
+struct H2Body(SendStream<Bytes>);
+
+impl AsyncWrite for H2Body {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.0.reserve_capacity(buf.len());
+        loop {
+            match self.0.poll_capacity(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io::Error::other(e))),
+                Poll::Ready(Some(Ok(0))) => continue,
+                Poll::Ready(Some(Ok(n))) => {
+                    let n = n.min(buf.len());
+                    self.0.send_data(Bytes::copy_from_slice(&buf[..n]), false).map_err(io::Error::other)?;
+                    return Poll::Ready(Ok(n));
+                },
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    //the end of the stream is sent by serve_stream once the whole body is out
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A stream with the bytes `sniff` read off it put back in front
+pub(super) struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), io::Error>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix[..n]);
+        self.prefix.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,198 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use h3::server::RequestStream;
+use log::*;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{IdleTimeout, VarInt};
+use rustls::pki_types::CertificateDer;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep_until, timeout, Instant};
+
+use super::http2::{answer, response_head};
+use super::{log_connection, until, Connections, Peer, Phase, ServerState, SharedState};
+use crate::config::Http3Config;
+use crate::http::Body;
+use crate::tls::Tls;
+
+//H3_NO_ERROR, RFC 9114 8.1
+const NO_ERROR: u32 = 0x100;
+
+//how far file reads get ahead of what flow control lets out
+const BODY_BUFFER: usize = 64 * 1024;
+
+type H3Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// A QUIC endpoint on `addr` with the certificates (and client verification) of `tls`
+pub(super) fn bind(addr: SocketAddr, tls: &Tls, config: &Http3Config) -> Result<quinn::Endpoint, io::Error> {
+    let crypto = QuicServerConfig::try_from(tls.quic_config()?).map_err(io::Error::other)?;
+    let idle_timeout = IdleTimeout::try_from(Duration::from_secs(config.idle_timeout)).map_err(io::Error::other)?;
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_concurrent_bidi_streams(VarInt::from_u32(config.max_concurrent_streams))
+        .max_idle_timeout(Some(idle_timeout));
+
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(transport));
+    quinn::Endpoint::server(server_config, addr)
+}
+
+/// What the TCP accept loop is for a QUIC endpoint, connections count towards the same shutdown
+pub(super) async fn accept_loop(
+    endpoint: quinn::Endpoint,
+    tls: Arc<Tls>,
+    state: SharedState,
+    connections: Arc<Connections>,
+    mut phase: watch::Receiver<Phase>,
+) -> Result<(), io::Error> {
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => {
+                let incoming = match incoming {
+                    Some(i) => i,
+                    None => return Ok(()),
+                };
+                let state = Arc::clone(&state.read().unwrap());
+                let guard = connections.track();
+                let (tls, phase) = (Arc::clone(&tls), phase.clone());
+
+                tokio::spawn(async move {
+                    let addr = incoming.remote_address();
+                    let handshake_timeout = Duration::from_secs(state.config.tls.as_ref().map_or(10, |t| t.handshake_timeout));
+                    let connecting = match incoming.accept() {
+                        Ok(c) => c,
+                        Err(e) => return debug!("QUIC connection from {} refused: {}", &addr, e),
+                    };
+                    match timeout(handshake_timeout, connecting).await {
+                        Ok(Ok(connection)) => {
+                            let chain = connection.peer_identity().and_then(|i| i.downcast::<Vec<CertificateDer<'static>>>().ok());
+                            let client_cert = tls.identify(chain.as_deref().map(Vec::as_slice));
+                            let peer = Peer { client_cert, secure: true, redirect_port: None };
+                            serve(connection, addr, peer, state, phase).await;
+                        },
+                        Ok(Err(e)) => debug!("QUIC handshake with {} failed: {}", &addr, e),
+                        Err(_) => debug!("QUIC handshake with {} timed out", &addr),
+                    }
+                    drop(guard);
+                });
+            },
+            _ = until(&mut phase, |p| *p != Phase::Running) => {
+                //handshakes in progress are turned away, established connections carry on
+                endpoint.set_server_config(None);
+                info!("No longer listening for HTTP/3 on {}", endpoint.local_addr()?);
+                return Ok(());
+            },
+        }
+    }
+}
+
+/// Serves an HTTP/3 connection, every request stream is answered on a task of its own
+///
+/// Idle connections are dropped by QUIC after `tls.http3.idle_timeout`. On shutdown the client is
+/// sent a GOAWAY and the connection closed once the requests it already made are answered.
+async fn serve(connection: quinn::Connection, addr: SocketAddr, peer: Peer, state: Arc<ServerState>, mut phase: watch::Receiver<Phase>) {
+    log_connection(&addr, &peer, "HTTP/3");
+    let peer = Arc::new(peer);
+
+    let built = h3::server::builder()
+        .max_field_section_size(state.config.http.max_header_size as u64)
+        .build::<_, Bytes>(h3_quinn::Connection::new(connection.clone()))
+        .await;
+    let mut h3 = match built {
+        Ok(h) => h,
+        Err(e) => return debug!("HTTP/3 setup with {} failed: {}", &addr, e),
+    };
+
+    //stream tasks report back here when they are done, so we know when a closing connection can go
+    let (done, mut finished) = mpsc::unbounded_channel();
+    let mut open = 0;
+    let mut served = 0;
+    let mut closing = false;
+    let mut quiet_since = Instant::now();
+    let mut abort = phase.clone();
+
+    loop {
+        //closing right after the last response would throw away whatever of it isn't acknowledged
+        //yet, a few round trips is what the client gets to receive it and hang up itself
+        let linger = connection.rtt() * 3;
+        tokio::select! {
+            accepted = h3.accept() => match accepted {
+                Ok(Some(resolver)) => {
+                    open += 1;
+                    served += 1;
+                    let (peer, state, done) = (Arc::clone(&peer), Arc::clone(&state), done.clone());
+                    tokio::spawn(async move {
+                        match resolver.resolve_request().await {
+                            Ok((request, stream)) => if let Err(e) = serve_stream(request, stream, peer, state).await {
+                                debug!("error answering HTTP/3 stream from {}: {}", &addr, e);
+                            },
+                            Err(e) => debug!("bad HTTP/3 request from {}: {}", &addr, e),
+                        }
+                        let _ = done.send(());
+                    });
+                },
+                Ok(None) => break,
+                //this is also how the client closing the connection or it timing out shows up
+                Err(e) => {
+                    debug!("HTTP/3 connection from {} closed after {} streams: {}", &addr, served, e);
+                    return;
+                },
+            },
+            Some(()) = finished.recv() => {
+                open -= 1;
+                quiet_since = Instant::now();
+            },
+            _ = until(&mut phase, |p| *p != Phase::Running), if !closing => {
+                debug!("closing HTTP/3 connection from {} for shutdown after {} streams", &addr, served);
+                if let Err(e) = h3.shutdown(0).await {
+                    debug!("unable to send GOAWAY to {}: {}", &addr, e);
+                }
+                closing = true;
+                quiet_since = Instant::now();
+            },
+            _ = sleep_until(quiet_since + linger), if closing && open == 0 => break,
+            _ = until(&mut abort, |p| *p == Phase::Aborting) => {
+                debug!("cut HTTP/3 connection to {} at the end of the shutdown grace period", &addr);
+                connection.close(VarInt::from_u32(NO_ERROR), b"shutting down");
+                return;
+            },
+        }
+    }
+    connection.close(VarInt::from_u32(NO_ERROR), b"");
+    debug!("HTTP/3 connection from {} closed after {} streams", &addr, served);
+}
+
+async fn serve_stream(request: http::Request<()>, mut stream: H3Stream, peer: Arc<Peer>, state: Arc<ServerState>) -> Result<(), io::Error> {
+    let (parts, ()) = request.into_parts();
+    let response = answer(&parts, peer, state).await?;
+    stream.send_response(response_head(&response)?).await.map_err(io::Error::other)?;
+
+    if response.sends_body() {
+        match response.body {
+            //cached bodies go out without being copied
+            Body::Bytes(b) => stream.send_data(b).await.map_err(io::Error::other)?,
+            //files go through a pipe, so a client whose flow control window is full holds back our
+            //reads like over TCP
+            body => {
+                let (mut writer, mut reader) = tokio::io::duplex(BODY_BUFFER);
+                let write = async move { body.write_to(&mut writer).await };
+                let send = async {
+                    let mut chunk = vec![0u8; BODY_BUFFER];
+                    loop {
+                        let n = reader.read(&mut chunk).await?;
+                        if n == 0 {
+                            return Ok(());
+                        }
+                        stream.send_data(Bytes::copy_from_slice(&chunk[..n])).await.map_err(io::Error::other)?;
+                    }
+                };
+                tokio::try_join!(write, send)?;
+            },
+        }
+    }
+    stream.finish().await.map_err(io::Error::other)
+}
@@ -0,0 +1,674 @@
+mod handler;
+mod http2;
+mod http3;
+mod redirect;
+mod vhost;
+
+use std::borrow::BorrowMut;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use log::*;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::acme::{Acme, Challenges};
+use crate::config::{ClientAuthConfig, Config, ConfigError};
+use crate::http::{self, HttpResponse, HttpStatusCode, ReadError, RequestReader};
+use crate::tls::{self, ClientCert};
+use vhost::Sites;
+
+/// Everything a connection needs to answer requests, a connection keeps the state it was accepted
+/// with so a reload only affects connections that come after it
+pub(crate) struct ServerState {
+    pub config: Config,
+    /// What requests are answered from, the top level settings and every `[[hosts]]` entry
+    pub sites: Sites,
+    /// HTTP-01 challenges `tls.acme` is waiting on, shared by every state the server goes through
+    pub challenges: Arc<Challenges>,
+    /// `Alt-Svc` value pointing HTTPS clients at the `tls.http3` listeners, set once they are bound
+    pub alt_svc: Option<String>,
+}
+
+/// How a connection reached us, which decides how its requests are answered
+pub(crate) struct Peer {
+    pub client_cert: ClientCert,
+    /// Came in over TLS, so responses carry `Strict-Transport-Security` when it is configured
+    pub secure: bool,
+    /// Came in on a `tls.redirect` listener, the port its redirects point at
+    pub redirect_port: Option<u16>,
+}
+
+//what an accept loop does with the connections it accepts
+#[derive(Clone)]
+enum Listener {
+    Plain,
+    Tls(Arc<tls::Tls>),
+    Redirect(u16),
+}
+
+impl ServerState {
+    fn new(config: Config, previous: Option<&ServerState>) -> Result<ServerState, ConfigError> {
+        let sites = Sites::new(&config, previous.map(|p| &p.sites))?;
+        let challenges = previous.map(|p| Arc::clone(&p.challenges)).unwrap_or_default();
+        let alt_svc = previous.and_then(|p| p.alt_svc.clone());
+        Ok(ServerState { config, sites, challenges, alt_svc })
+    }
+}
+
+type SharedState = Arc<RwLock<Arc<ServerState>>>;
+
+/// Swaps new settings into a running server, cheap to clone and hand to whatever triggers reloads
+#[derive(Clone)]
+pub struct Reloader {
+    state: SharedState,
+    //held while a reload builds its state, which happens outside the lock the accept loops read
+    reloading: Arc<Mutex<()>>,
+}
+
+impl Reloader {
+    /// Validates `config` and makes it the one new connections are served with, on error the
+    /// running config is left alone. Listen addresses and `[tls]` are set up at startup and can't
+    /// change here, except for `tls.client_auth` paths and allow which are checked per request.
+    /// Adding or removing `tls.client_auth` or changing its ca is an error.
+    pub fn reload(&self, mut config: Config) -> Result<(), ConfigError> {
+        let _reloading = self.reloading.lock().unwrap();
+        let current = self.current();
+        if config.server.listen != current.config.server.listen {
+            warn!("server.listen only takes effect on restart, still listening on {:?}", current.config.server.listen);
+            config.server.listen = current.config.server.listen.clone();
+        }
+        let mut tls = current.config.tls.clone();
+        if let Some(t) = tls.as_mut() {
+            t.client_auth = reloaded_client_auth(&config, &current.config)?;
+        }
+        if config.tls != tls {
+            warn!("[tls] only takes effect on restart apart from tls.client_auth paths and allow, keeping the running TLS settings");
+        }
+        config.tls = tls;
+        config.validate()?;
+
+        //setting up the file caches touches the disk, new connections keep getting the running
+        //state until it is done
+        let state = ServerState::new(config, Some(&current))?;
+        *self.state.write().unwrap() = Arc::new(state);
+        info!("Configuration reloaded");
+        Ok(())
+    }
+
+    /// `Config::load` then `reload`
+    pub fn reload_file<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        self.reload(Config::load(path)?)
+    }
+
+    /// The config new connections are currently getting
+    pub fn config(&self) -> Config {
+        self.current().config.clone()
+    }
+
+    fn current(&self) -> Arc<ServerState> {
+        Arc::clone(&self.state.read().unwrap())
+    }
+}
+
+//whether the handshake asks for a certificate and which CAs it takes are fixed at startup, so only
+//a client_auth with the running ca can be swapped in
+fn reloaded_client_auth(config: &Config, running: &Config) -> Result<Option<ClientAuthConfig>, ConfigError> {
+    let client_auth = |c: &Config| c.tls.as_ref().and_then(|t| t.client_auth.clone());
+    match (client_auth(config), client_auth(running)) {
+        (Some(new), Some(old)) if new.ca == old.ca => Ok(Some(new)),
+        (None, None) => Ok(None),
+        (Some(_), Some(old)) => Err(ConfigError::Invalid(format!(
+            "tls.client_auth.ca only takes effect on restart, the running one is {}",
+            old.ca.display()
+        ))),
+        (new, _) => Err(ConfigError::Invalid(format!(
+            "tls.client_auth can only be {} on restart",
+            if new.is_some() { "added" } else { "removed" }
+        ))),
+    }
+}
+
+/// How a shutdown went, so callers (and the exit status) can tell a clean drain from a cut one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shutdown {
+    /// Every open connection finished within the grace period
+    Drained,
+    /// These many connections were still open when the grace period ran out and were cut
+    Aborted { connections: usize },
+}
+
+//where a server is in its life, connections watch this to know when to stop
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Running,
+    //no new connections, idle ones are closed and busy ones finish their current response
+    Draining,
+    Aborting,
+}
+
+//open connection count, shutdown waits on it falling to zero
+struct Connections {
+    open: Mutex<usize>,
+    count: watch::Sender<usize>,
+    count_rx: watch::Receiver<usize>,
+}
+
+//held by a connection's task for as long as it runs
+struct ConnectionGuard(Arc<Connections>);
+
+impl Connections {
+    fn new() -> Arc<Connections> {
+        let (count, count_rx) = watch::channel(0);
+        Arc::new(Connections { open: Mutex::new(0), count, count_rx })
+    }
+
+    fn track(self: &Arc<Self>) -> ConnectionGuard {
+        let mut open = self.open.lock().unwrap();
+        *open += 1;
+        let _ = self.count.send(*open);
+        ConnectionGuard(Arc::clone(self))
+    }
+
+    fn open(&self) -> usize {
+        *self.open.lock().unwrap()
+    }
+
+    async fn drained(&self) {
+        let mut count = self.count_rx.clone();
+        while *count.borrow() > 0 {
+            if count.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.0.open.lock().unwrap();
+        *open -= 1;
+        let _ = self.0.count.send(*open);
+    }
+}
+
+//resolves once the phase satisfies done, or straight away if the handle has been dropped
+async fn until<F: Fn(&Phase) -> bool>(phase: &mut watch::Receiver<Phase>, done: F) {
+    while !done(&phase.borrow()) {
+        if phase.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sets up a `Server`, anything not overridden here comes from the `Config`
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use webserv::{config::Config, Server};
+///
+/// let handle = Server::builder(Config::default())
+///     .listen("127.0.0.1:0".parse()?)
+///     .build()?
+///     .start()
+///     .await?;
+/// println!("serving on {}", handle.local_addr());
+/// handle.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ServerBuilder {
+    config: Config,
+    listen: Vec<SocketAddr>,
+    doc_root: Option<PathBuf>,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config,
+            listen: Vec::new(),
+            doc_root: None,
+        }
+    }
+
+    /// Adds an address to listen on, replacing `server.listen` from the config. Port 0 picks an
+    /// ephemeral port, the handle reports what was actually bound.
+    pub fn listen(mut self, addr: SocketAddr) -> ServerBuilder {
+        self.listen.push(addr);
+        self
+    }
+
+    /// Replaces `server.doc_root` from the config
+    pub fn doc_root<P: Into<PathBuf>>(mut self, doc_root: P) -> ServerBuilder {
+        self.doc_root = Some(doc_root.into());
+        self
+    }
+
+    /// Validates the final config and sets up a `FileCache` for every site, nothing is bound yet.
+    /// A doc root that can't be watched is an error as well.
+    pub fn build(self) -> Result<Server, ConfigError> {
+        let mut config = self.config;
+        if !self.listen.is_empty() {
+            config.server.listen = self.listen;
+        }
+        if let Some(doc_root) = self.doc_root {
+            config.server.doc_root = doc_root;
+        }
+        config.validate()?;
+
+        Ok(Server { state: ServerState::new(config, None)? })
+    }
+}
+
+/// A configured server with its own file caches, any number of them can run in one process
+pub struct Server {
+    state: ServerState,
+}
+
+impl Server {
+    pub fn builder(config: Config) -> ServerBuilder {
+        ServerBuilder::new(config)
+    }
+
+    /// Binds every listen address and starts serving on the current tokio runtime
+    pub async fn start(self) -> Result<ServerHandle, std::io::Error> {
+        let mut state = self.state;
+
+        let mut listeners = Vec::with_capacity(state.config.server.listen.len());
+        let mut local_addrs = Vec::with_capacity(state.config.server.listen.len());
+        for addr in &state.config.server.listen {
+            let listener = TcpListener::bind(addr).await?;
+            local_addrs.push(listener.local_addr()?);
+            info!("Listening on {}", listener.local_addr()?);
+            listeners.push((listener, Listener::Plain));
+        }
+
+        let mut tls_addrs = Vec::new();
+        let mut redirect_addrs = Vec::new();
+        let mut http3_addrs = Vec::new();
+        let mut endpoints = Vec::new();
+        let mut tls = None;
+        let mut acme = None;
+        if let Some(tls_config) = &state.config.tls {
+            if let Some(acme_config) = &tls_config.acme {
+                Acme::prepare(tls_config, acme_config)?;
+            }
+            let t = Arc::new(tls::Tls::new(tls_config, state.config.http2.enabled)?);
+            for addr in &tls_config.listen {
+                let listener = TcpListener::bind(addr).await?;
+                tls_addrs.push(listener.local_addr()?);
+                info!("Listening for HTTPS on {}", listener.local_addr()?);
+                listeners.push((listener, Listener::Tls(Arc::clone(&t))));
+            }
+
+            if let Some(http3) = &tls_config.http3 {
+                for addr in &http3.listen {
+                    let endpoint = http3::bind(*addr, &t, http3)?;
+                    http3_addrs.push(endpoint.local_addr()?);
+                    info!("Listening for HTTP/3 on {}", endpoint.local_addr()?);
+                    endpoints.push((endpoint, Arc::clone(&t)));
+                }
+                let port = http3.alt_svc_port.unwrap_or(http3_addrs[0].port());
+                state.alt_svc = Some(format!("h3=\":{}\"; ma={}", port, http3.alt_svc_max_age));
+            }
+            tls = Some(t);
+
+            if let Some(redirect) = &tls_config.redirect {
+                //validate() made sure there is a port to point at, either configured or listened on
+                let https_port = redirect.https_port.or_else(|| tls_addrs.first().map(|a| a.port())).unwrap_or(443);
+                for addr in &redirect.listen {
+                    let listener = TcpListener::bind(addr).await?;
+                    redirect_addrs.push(listener.local_addr()?);
+                    info!("Redirecting to HTTPS from {}", listener.local_addr()?);
+                    listeners.push((listener, Listener::Redirect(https_port)));
+                }
+            }
+
+            //challenges can be answered from here on
+            if let Some(acme_config) = &tls_config.acme {
+                acme = Some(Acme::start(tls_config, acme_config, Arc::clone(&state.challenges))?);
+            }
+        }
+
+        let state: SharedState = Arc::new(RwLock::new(Arc::new(state)));
+        let reloader = Reloader { state: Arc::clone(&state), reloading: Arc::new(Mutex::new(())) };
+        let (phase, phase_rx) = watch::channel(Phase::Running);
+        let connections = Connections::new();
+
+        let stats_interval = state.read().unwrap().config.cache.stats_interval;
+        let stats_task = stats_interval.map(|secs| {
+            let reloader = reloader.clone();
+            let mut phase_rx = phase_rx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(secs));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => for site in reloader.current().sites.all() {
+                            match site.name.as_str() {
+                                "" => info!("FileCache: {}", site.filecache.stats()),
+                                name => info!("FileCache for {}: {}", name, site.filecache.stats()),
+                            }
+                        },
+                        _ = until(&mut phase_rx, |p| *p != Phase::Running) => return,
+                    }
+                }
+            })
+        });
+
+        let mut accept_loops = Vec::with_capacity(listeners.len());
+        for (listener, kind) in listeners {
+            let state = Arc::clone(&state);
+            let connections = Arc::clone(&connections);
+            let phase_rx = phase_rx.clone();
+            accept_loops.push(tokio::spawn(async move { accept_loop(listener, kind, state, connections, phase_rx).await }));
+        }
+        for (endpoint, tls) in endpoints {
+            let (state, connections) = (Arc::clone(&state), Arc::clone(&connections));
+            accept_loops.push(tokio::spawn(http3::accept_loop(endpoint, tls, state, connections, phase_rx.clone())));
+        }
+
+        Ok(ServerHandle { local_addrs, tls_addrs, redirect_addrs, http3_addrs, tls, acme, reloader, phase, connections, accept_loops, stats_task })
+    }
+}
+
+/// A running server, dropping it stops the server and cuts its open connections without waiting
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    tls_addrs: Vec<SocketAddr>,
+    redirect_addrs: Vec<SocketAddr>,
+    http3_addrs: Vec<SocketAddr>,
+    tls: Option<Arc<tls::Tls>>,
+    acme: Option<Acme>,
+    reloader: Reloader,
+    phase: watch::Sender<Phase>,
+    connections: Arc<Connections>,
+    accept_loops: Vec<JoinHandle<Result<(), std::io::Error>>>,
+    stats_task: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The first bound address (plain HTTP ahead of HTTPS), handy when a single port 0 listener was asked for
+    pub fn local_addr(&self) -> SocketAddr {
+        *self.local_addrs.iter().chain(&self.tls_addrs).next().unwrap()
+    }
+
+    /// Plain HTTP listeners
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// HTTPS listeners
+    pub fn tls_addrs(&self) -> &[SocketAddr] {
+        &self.tls_addrs
+    }
+
+    /// The addresses of the `tls.redirect` listeners
+    pub fn redirect_addrs(&self) -> &[SocketAddr] {
+        &self.redirect_addrs
+    }
+
+    /// The UDP addresses of the `tls.http3` listeners
+    pub fn http3_addrs(&self) -> &[SocketAddr] {
+        &self.http3_addrs
+    }
+
+    /// For changing the config while the server runs, see `Reloader::reload`
+    pub fn reloader(&self) -> Reloader {
+        self.reloader.clone()
+    }
+
+    /// Connections currently open
+    pub fn connections(&self) -> usize {
+        self.connections.open()
+    }
+
+    /// Stops accepting and lets open connections finish, cutting whatever is left once
+    /// `server.shutdown_grace` runs out. The file caches' notify threads are stopped before returning.
+    pub async fn shutdown(mut self) -> Result<Shutdown, std::io::Error> {
+        let _ = self.phase.send(Phase::Draining);
+        let listeners = self.listeners_stopped().await;
+
+        let current = self.reloader().current();
+        let grace = Duration::from_secs(current.config.server.shutdown_grace);
+        info!("Shutting down, draining {} connections", self.connections.open());
+        let outcome = match timeout(grace, self.connections.drained()).await {
+            Ok(()) => Shutdown::Drained,
+            Err(_) => {
+                let connections = self.connections.open();
+                warn!("Cutting {} connections still open after {:?}", connections, grace);
+                let _ = self.phase.send(Phase::Aborting);
+                self.connections.drained().await;
+                Shutdown::Aborted { connections }
+            },
+        };
+
+        if let Some(t) = self.stats_task.take() {
+            let _ = t.await;
+        }
+        //caches replaced by a reload stopped watching when their last connection let go of them
+        for site in current.sites.all() {
+            site.filecache.close();
+        }
+        if let Some(acme) = &self.acme {
+            acme.close();
+        }
+        if let Some(tls) = &self.tls {
+            tls.close();
+        }
+
+        listeners.map(|_| outcome)
+    }
+
+    /// Serves until `signal` resolves (or a listener fails) and then shuts down as `shutdown` does
+    pub async fn shutdown_on<F: Future<Output = ()>>(mut self, signal: F) -> Result<Shutdown, std::io::Error> {
+        let failed = tokio::select! {
+            r = self.listeners_stopped() => r.err(),
+            _ = signal => None,
+        };
+        let outcome = self.shutdown().await;
+        match failed {
+            Some(e) => Err(e),
+            None => outcome,
+        }
+    }
+
+    //finished loops are removed as they are collected so this can be cancelled and called again
+    async fn listeners_stopped(&mut self) -> Result<(), std::io::Error> {
+        while let Some(l) = self.accept_loops.last_mut() {
+            let result = l.await;
+            self.accept_loops.pop();
+            match result {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => return Err(e),
+                Err(e) => return Err(std::io::Error::other(e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    kind: Listener,
+    state: SharedState,
+    connections: Arc<Connections>,
+    mut phase: watch::Receiver<Phase>,
+) -> Result<(), std::io::Error> {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, addr) = accepted?;
+                let state = Arc::clone(&state.read().unwrap());
+                let guard = connections.track();
+                let phase = phase.clone();
+                let kind = kind.clone();
+
+                tokio::spawn(async move {
+                    let plain = |redirect_port| Peer { client_cert: ClientCert::None, secure: false, redirect_port };
+                    match kind {
+                        //h2c is only spoken to clients that open with the preface, everyone else gets HTTP/1.x
+                        Listener::Plain if state.config.http2.h2c => {
+                            let wait = Duration::from_secs(state.config.http.keep_alive_timeout);
+                            match http2::sniff(stream, wait).await {
+                                Ok((stream, true)) => http2::serve(stream, addr, plain(None), state, phase).await,
+                                Ok((stream, false)) => handle_connection(Box::new((stream, addr)), plain(None), state, phase).await,
+                                Err(e) => debug!("received an error on bytes read: {} from {}", e, &addr),
+                            }
+                        },
+                        Listener::Plain => handle_connection(Box::new((stream, addr)), plain(None), state, phase).await,
+                        Listener::Redirect(port) => handle_connection(Box::new((stream, addr)), plain(Some(port)), state, phase).await,
+                        Listener::Tls(tls) => match tls.accept(stream).await {
+                            Ok((stream, client_cert)) => {
+                                let peer = Peer { client_cert, secure: true, redirect_port: None };
+                                match stream.get_ref().1.alpn_protocol() == Some(tls::ALPN_H2) {
+                                    true => http2::serve(stream, addr, peer, state, phase).await,
+                                    false => handle_connection(Box::new((stream, addr)), peer, state, phase).await,
+                                }
+                            },
+                            Err(e) => debug!("TLS handshake with {} failed: {}", &addr, e),
+                        },
+                    }
+                    drop(guard);
+                });
+            },
+            _ = until(&mut phase, |p| *p != Phase::Running) => {
+                info!("No longer listening on {}", listener.local_addr()?);
+                return Ok(());
+            },
+        }
+    }
+}
+
+async fn handle_connection<S>(
+    mut boxed_result: Box<(S, SocketAddr)>,
+    peer: Peer,
+    state: Arc<ServerState>,
+    mut phase: watch::Receiver<Phase>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (stream, addr) = boxed_result.borrow_mut();
+    log_connection(addr, &peer, "HTTP/1.1");
+    let peer = Arc::new(peer);
+
+    let config = &state.config;
+    let idle_timeout = Duration::from_secs(config.http.keep_alive_timeout);
+    let mut reader = RequestReader::new(config.http.max_header_size);
+    let mut served = 0;
+
+    loop {
+        //a connection waiting on its next request has nothing in flight, so shutdown can close it right away
+        let read = tokio::select! {
+            r = timeout(idle_timeout, reader.read_head(stream)) => r,
+            _ = until(&mut phase, |p| *p != Phase::Running) => {
+                debug!("closing connection from {} for shutdown after {} requests", &addr, served);
+                break;
+            },
+        };
+        let head = match read {
+            Ok(Ok(h)) => {
+                debug!("received {} byte request head from {}", h.len(), &addr);
+                Ok(h)
+            },
+            Ok(Err(ReadError::Closed)) => {
+                debug!("{} closed the connection after {} requests", &addr, served);
+                break;
+            },
+            Ok(Err(ReadError::Io(e))) => {
+                debug!("received an error on bytes read: {} from {}", e, &addr);
+                return;
+            },
+            Ok(Err(ReadError::Incomplete)) => Err(HttpStatusCode::BadRequest),
+            Ok(Err(ReadError::TooLarge)) => Err(HttpStatusCode::RequestHeaderFieldsTooLarge),
+            Err(_) => {
+                debug!("closing idle connection from {} after {} requests", &addr, served);
+                break;
+            },
+        };
+        served += 1;
+
+        //a head we couldn't even read leaves the stream in an unknown state, so it is never reused
+        let keep_alive = match &head {
+            Ok(h) => http::is_persistent(h) && served < config.http.max_requests && *phase.borrow() == Phase::Running,
+            Err(_) => false,
+        };
+        //checked on the raw head so errors answering a HEAD (a 404, say) don't get a body either
+        let head_only = matches!(&head, Ok(h) if h.starts_with("HEAD "));
+
+        let blocking_state = Arc::clone(&state);
+        let blocking_peer = Arc::clone(&peer);
+        let mut response = match tokio::task::spawn_blocking(move || handler::respond(head, &blocking_peer, &blocking_state)).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("request handler for {} failed: {}", &addr, e);
+                return;
+            },
+        };
+        if head_only {
+            response = response.without_body();
+        }
+        response.headers.set("Connection", connection_token(keep_alive));
+        add_common_headers(&mut response, &state, &peer);
+
+        let written = tokio::select! {
+            r = write_response(stream, response) => r,
+            _ = until(&mut phase, |p| *p == Phase::Aborting) => {
+                debug!("cut response to {} at the end of the shutdown grace period", &addr);
+                return;
+            },
+        };
+        if let Err(e) = written {
+            debug!("error writing response to {}: {}", &addr, e);
+            return;
+        }
+
+        if !keep_alive {
+            debug!("closing connection from {} after {} requests", &addr, served);
+            break;
+        }
+    }
+
+    //sends close_notify over TLS so clients can tell the end of the stream from a truncation
+    if let Err(e) = timeout(idle_timeout, stream.shutdown()).await.unwrap_or_else(|e| Err(e.into())) {
+        debug!("error closing connection to {}: {}", &addr, e);
+    }
+}
+
+fn log_connection(addr: &SocketAddr, peer: &Peer, protocol: &str) {
+    match &peer.client_cert {
+        ClientCert::None => info!("New {} client connection from {}", protocol, addr),
+        ClientCert::Verified(id) => info!("New {} client connection from {} with client certificate {}", protocol, addr, id),
+        ClientCert::Rejected(e) => info!("New {} client connection from {} with a rejected client certificate: {}", protocol, addr, e),
+    }
+}
+
+//the headers every response gets, whichever protocol it goes out over
+fn add_common_headers(response: &mut HttpResponse, state: &ServerState, peer: &Peer) {
+    let config = &state.config;
+    if config.mime.nosniff {
+        response.headers.set("X-Content-Type-Options", "nosniff");
+    }
+    if let Some(hsts) = config.tls.as_ref().and_then(|t| t.hsts.as_ref()).filter(|_| peer.secure) {
+        response.headers.set("Strict-Transport-Security", hsts.header_value());
+    }
+    if let Some(alt_svc) = state.alt_svc.as_ref().filter(|_| peer.secure) {
+        response.headers.set("Alt-Svc", alt_svc.clone());
+    }
+}
+
+fn connection_token(keep_alive: bool) -> &'static str {
+    if keep_alive { "keep-alive" } else { "close" }
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(stream: &mut S, response: HttpResponse) -> Result<(), std::io::Error> {
+    response.write_to(stream).await?;
+    stream.flush().await
+}
@@ -0,0 +1,45 @@
+use log::*;
+
+use crate::config::RedirectConfig;
+use crate::http::{self, HttpResponse, HttpStatusCode};
+use super::vhost::host_name;
+
+/// Answers a request that came in on a `tls.redirect` listener with a redirect to the same target
+/// over HTTPS, None when it should be served like on any other listener (one of the exceptions, or
+/// a head too broken to redirect which the regular parser turns into the right error)
+pub(crate) fn redirect(head: &str, https_port: u16, config: &RedirectConfig) -> Option<Result<HttpResponse, HttpStatusCode>> {
+    let (method, target, headers) = http::split_head(head)?;
+
+    let (authority, target) = http::split_target(target);
+    let authority = authority.or_else(|| headers.get("host"));
+    //`OPTIONS *` and friends aren't about a resource, there is nothing to point them at
+    if !target.starts_with('/') {
+        return None;
+    }
+
+    let path = target.split('?').next().unwrap_or_default();
+    if config.exceptions.iter().any(|e| path.starts_with(e.as_str())) {
+        return None;
+    }
+
+    let host = match authority.and_then(host_name) {
+        Some(h) => h,
+        None => {
+            debug!("can't redirect {} without a usable host", target);
+            return Some(Err(HttpStatusCode::BadRequest));
+        },
+    };
+    let location = match https_port {
+        443 => format!("https://{}{}", host, target),
+        port => format!("https://{}:{}{}", host, port, target),
+    };
+
+    //301 lets clients turn a POST into a GET, so only GET and HEAD get the configured status
+    let status = match (method, config.status) {
+        ("GET", 301) | ("HEAD", 301) => HttpStatusCode::MovedPermanently,
+        _ => HttpStatusCode::PermanentRedirect,
+    };
+    let mut response = HttpResponse::new(status);
+    response.headers.set("Location", location);
+    Some(Ok(response))
+}
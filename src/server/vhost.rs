@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::*;
+
+use crate::config::{Config, ConfigError, HostConfig};
+use crate::filestore::FileCache;
+use crate::http::{self, HttpStatusCode, MimeTypes};
+
+/// What requests are answered from: a doc root, the cache in front of it and the settings that go
+/// with them
+pub(crate) struct Site {
+    /// First of the host's names, empty for the top level settings
+    pub name: String,
+    /// The top level config with the host's doc root, index, error pages and client_auth in place,
+    /// so the request and error page code doesn't have to know about hosts
+    pub config: Config,
+    pub filecache: Arc<FileCache>,
+    /// `hosts.headers`, set on every response
+    pub headers: Vec<(String, String)>,
+}
+
+impl Site {
+    //the previous site's cache (and what it has loaded) is kept when nothing it depends on changed
+    fn new(name: String, config: Config, headers: Vec<(String, String)>, previous: Option<&Site>) -> Result<Site, ConfigError> {
+        let filecache = match previous {
+            Some(p) if p.config.server.doc_root == config.server.doc_root
+                && p.config.cache == config.cache
+                && p.config.mime == config.mime => Arc::clone(&p.filecache),
+            _ => {
+                let doc_root = &config.server.doc_root;
+                let cache = FileCache::new(doc_root.to_str().unwrap(), MimeTypes::new(&config.mime), config.cache.clone())
+                    .map_err(|e| ConfigError::Invalid(format!("unable to watch doc root {}: {}", doc_root.display(), e)))?;
+                Arc::new(cache)
+            },
+        };
+        Ok(Site { name, config, filecache, headers })
+    }
+}
+
+/// The top level site and the `[[hosts]]`, with the names that pick them
+pub(crate) struct Sites {
+    main: Site,
+    hosts: Vec<Site>,
+    names: HashMap<String, usize>,
+    //keyed by what follows the `*.`
+    wildcards: HashMap<String, usize>,
+    default: Option<usize>,
+    strict: bool,
+}
+
+impl Sites {
+    /// Every host gets a cache (and file watcher) of its own, except that a reload hands a host
+    /// the one it had before when its doc root and the cache settings didn't change. Fails when a
+    /// doc root can't be watched.
+    pub fn new(config: &Config, previous: Option<&Sites>) -> Result<Sites, ConfigError> {
+        let mut names = HashMap::new();
+        let mut wildcards = HashMap::new();
+        let mut hosts = Vec::with_capacity(config.hosts.len());
+        for (i, host) in config.hosts.iter().enumerate() {
+            for name in &host.names {
+                match name.strip_prefix("*.") {
+                    Some(parent) => wildcards.insert(parent.to_string(), i),
+                    None => names.insert(name.clone(), i),
+                };
+            }
+            let name = host.names[0].clone();
+            let headers = host.headers.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+            let before = previous.and_then(|p| p.hosts.iter().find(|s| s.name == name));
+            hosts.push(Site::new(name, host_config(config, host), headers, before)?);
+        }
+
+        Ok(Sites {
+            main: Site::new(String::new(), config.clone(), Vec::new(), previous.map(|p| &p.main))?,
+            hosts,
+            names,
+            wildcards,
+            default: config.hosts.iter().position(|h| h.default),
+            strict: config.server.strict_hosts,
+        })
+    }
+
+    /// The site a request head is for, picked by the host it names (case and port don't matter)
+    /// with exact names ahead of wildcards. Under `server.strict_hosts` a request that names no
+    /// host gets a 400 and one for a name no host claims a 421, otherwise they go to the default.
+    pub fn select(&self, head: &str) -> Result<&Site, HttpStatusCode> {
+        if self.hosts.is_empty() {
+            return Ok(&self.main);
+        }
+        let host = match request_host(head) {
+            Some(h) => h,
+            None if self.strict => return Err(HttpStatusCode::BadRequest),
+            None => return Ok(self.fallback()),
+        };
+        let found = self.names.get(&host)
+            .or_else(|| host.split_once('.').and_then(|(_, parent)| self.wildcards.get(parent)));
+        match found {
+            Some(&i) => Ok(&self.hosts[i]),
+            None if self.strict => {
+                debug!("no host is configured for {}", host);
+                Err(HttpStatusCode::MisdirectedRequest)
+            },
+            None => Ok(self.fallback()),
+        }
+    }
+
+    /// The default host, or the top level settings when there is none. Requests that can't be
+    /// read well enough to pick a site are answered from here.
+    pub fn fallback(&self) -> &Site {
+        self.default.map(|i| &self.hosts[i]).unwrap_or(&self.main)
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &Site> {
+        std::iter::once(&self.main).chain(&self.hosts)
+    }
+}
+
+//the top level config with what a host sets in its place
+fn host_config(config: &Config, host: &HostConfig) -> Config {
+    let mut site = config.clone();
+    site.server.doc_root = host.doc_root.clone();
+    if let Some(index) = &host.index {
+        site.server.index = index.clone();
+    }
+    site.error_pages.extend(host.error_pages.clone());
+    if let (Some(auth), Some(tls_auth)) = (&host.client_auth, site.tls.as_mut().and_then(|t| t.client_auth.as_mut())) {
+        tls_auth.paths = auth.paths.clone();
+        tls_auth.allow = auth.allow.clone();
+    }
+    site.hosts.clear();
+    site
+}
+
+//lowercased with the port and any trailing dot dropped, None when the request names no usable host
+fn request_host(head: &str) -> Option<String> {
+    let (_, target, headers) = http::split_head(head)?;
+    let authority = match http::split_target(target) {
+        (Some(authority), _) => authority,
+        (None, _) => headers.get("host")?,
+    };
+    let host = host_name(authority)?.trim_end_matches('.');
+    Some(host.to_ascii_lowercase())
+}
+
+/// The host part of a Host header (or absolute-form authority) with its port dropped, None when
+/// it holds anything that doesn't belong in a host name or IP literal
+pub(super) fn host_name(authority: &str) -> Option<&str> {
+    let host = match authority.strip_prefix('[') {
+        Some(rest) => &authority[..rest.find(']')? + 2],
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+    let valid = host.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._:[]".contains(&b));
+    if host.is_empty() || !valid {
+        return None;
+    }
+    Some(host)
+}
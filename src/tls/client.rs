@@ -0,0 +1,158 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme};
+use rustls::client::danger::HandshakeSignatureValid;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::{load_certs, TlsError};
+
+/// What a connection's client certificate turned out to be, plain HTTP connections are `None`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientCert {
+    None,
+    Verified(ClientIdentity),
+    /// Presented but it doesn't chain to the configured CA (or has expired, etc)
+    Rejected(String),
+}
+
+/// The parts of a verified client certificate we log and check access against
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+    /// RFC 4514 style, `CN=ops, O=Example`
+    pub subject: String,
+    /// DNS names, emails, URIs and IP addresses from subjectAltName
+    pub sans: Vec<String>,
+}
+
+impl ClientIdentity {
+    fn from_der(der: &CertificateDer<'_>) -> Result<ClientIdentity, String> {
+        let (_, cert) = X509Certificate::from_der(der.as_ref()).map_err(|e| e.to_string())?;
+
+        let sans = match cert.subject_alternative_name() {
+            Ok(Some(ext)) => ext.value.general_names.iter()
+                .filter_map(|n| match n {
+                    GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => Some(s.to_string()),
+                    GeneralName::IPAddress(b) => ip_to_string(b),
+                    _ => None,
+                })
+                .collect(),
+            Ok(None) => Vec::new(),
+            Err(e) => return Err(e.to_string()),
+        };
+        Ok(ClientIdentity { subject: cert.subject().to_string(), sans })
+    }
+
+    /// True for an empty list, otherwise the subject or a SAN has to be in it
+    pub fn allowed_by(&self, allow: &[String]) -> bool {
+        allow.is_empty() || allow.iter().any(|a| *a == self.subject || self.sans.contains(a))
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "subject=\"{}\" san=[{}]", self.subject, self.sans.join(", "))
+    }
+}
+
+fn ip_to_string(b: &[u8]) -> Option<String> {
+    match b.len() {
+        4 => Some(std::net::Ipv4Addr::new(b[0], b[1], b[2], b[3]).to_string()),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(b);
+            Some(std::net::Ipv6Addr::from(octets).to_string())
+        },
+        _ => None,
+    }
+}
+
+/// Asks for a client certificate but lets the handshake through whatever is presented
+///
+/// Refusing the handshake would leave the client with a TLS alert instead of our 403 page, and
+/// most paths don't need a certificate anyway. The possession proof (CertificateVerify) is still
+/// checked here, the chain is checked by `identify` once the handshake is done.
+#[derive(Debug)]
+pub struct LenientVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+}
+
+impl LenientVerifier {
+    pub fn new(ca: &Path, provider: Arc<CryptoProvider>) -> Result<LenientVerifier, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(cert).map_err(TlsError::Rustls)?;
+        }
+        let inner = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .allow_unauthenticated()
+            .build()
+            .map_err(|e| TlsError::Rustls(rustls::Error::General(e.to_string())))?;
+        Ok(LenientVerifier { inner })
+    }
+
+    /// Verifies the chain a client presented against the CA
+    pub fn identify(&self, chain: Option<&[CertificateDer<'_>]>) -> ClientCert {
+        let (end_entity, intermediates) = match chain.and_then(|c| c.split_first()) {
+            Some(c) => c,
+            None => return ClientCert::None,
+        };
+        if let Err(e) = self.inner.verify_client_cert(end_entity, intermediates, UnixTime::now()) {
+            return ClientCert::Rejected(e.to_string());
+        }
+        match ClientIdentity::from_der(end_entity) {
+            Ok(identity) => ClientCert::Verified(identity),
+            Err(e) => ClientCert::Rejected(e),
+        }
+    }
+}
+
+impl ClientCertVerifier for LenientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
@@ -0,0 +1,215 @@
+mod client;
+mod resolver;
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::crypto::CryptoProvider;
+use rustls::{ServerConfig, SupportedProtocolVersion};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{TlsConfig, TlsVersion};
+pub use client::ClientCert;
+use client::LenientVerifier;
+pub use resolver::CertResolver;
+
+/// ALPN protocol id of HTTP/2 over TLS
+pub static ALPN_H2: &[u8] = b"h2";
+/// ALPN protocol id of HTTP/3, the only one offered over QUIC
+pub static ALPN_H3: &[u8] = b"h3";
+static ALPN_HTTP11: &[u8] = b"http/1.1";
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(PathBuf, std::io::Error),
+    NoCertificates(PathBuf),
+    NoKey(PathBuf),
+    Rustls(rustls::Error),
+    Watch(String),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(p, e) => write!(f, "unable to read {}: {}", p.display(), e),
+            TlsError::NoCertificates(p) => write!(f, "no PEM certificates found in {}", p.display()),
+            TlsError::NoKey(p) => write!(f, "no PEM private key found in {}", p.display()),
+            TlsError::Rustls(e) => write!(f, "invalid TLS setup: {}", e),
+            TlsError::Watch(e) => write!(f, "unable to watch certificate files: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<TlsError> for std::io::Error {
+    fn from(e: TlsError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// The HTTPS side of a server, an acceptor whose certificates follow the files on disk
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    provider: Arc<CryptoProvider>,
+    resolver: Arc<CertResolver>,
+    handshake_timeout: Duration,
+    client_verifier: Option<Arc<LenientVerifier>>,
+    //taken by close()
+    watcher: Mutex<Option<RecommendedWatcher>>,
+    watch_thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Tls {
+    /// Loads every certificate in `config` and starts watching their files, `http2` offers h2 in
+    /// ALPN ahead of http/1.1
+    ///
+    /// Cipher suites and key exchange groups are rustls' defaults with the ring provider, which
+    /// only has AEAD suites with forward secrecy, as RFC 9113 9.2 asks of HTTP/2.
+    pub fn new(config: &TlsConfig, http2: bool) -> Result<Tls, TlsError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let resolver = Arc::new(CertResolver::new(config, Arc::clone(&provider))?);
+
+        let versions: &[&SupportedProtocolVersion] = match config.min_version {
+            TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+            TlsVersion::Tls13 => &[&rustls::version::TLS13],
+        };
+        let client_verifier = match &config.client_auth {
+            Some(auth) => Some(Arc::new(LenientVerifier::new(&auth.ca, Arc::clone(&provider))?)),
+            None => None,
+        };
+
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_protocol_versions(versions)
+            .map_err(TlsError::Rustls)?;
+        let builder = match &client_verifier {
+            Some(v) => builder.with_client_cert_verifier(Arc::clone(v) as Arc<_>),
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder.with_cert_resolver(Arc::clone(&resolver) as Arc<_>);
+        server_config.alpn_protocols = match http2 {
+            true => vec![ALPN_H2.to_vec(), ALPN_HTTP11.to_vec()],
+            false => vec![ALPN_HTTP11.to_vec()],
+        };
+
+        //renewals tend to write a new file and rename it into place (or repoint a symlink), so
+        //it's the directories holding the files that get watched, symlink targets included
+        let dirs = resolver.files()
+            .flat_map(|f| vec![f.parent().map(Path::to_path_buf), f.canonicalize().ok().and_then(|c| c.parent().map(Path::to_path_buf))])
+            .flatten()
+            .map(|d| if d.as_os_str().is_empty() { PathBuf::from(".") } else { d })
+            .collect::<BTreeSet<PathBuf>>();
+
+        let (tx, rx) = channel();
+        let mut watcher: RecommendedWatcher = notify::Watcher::new(tx, Duration::from_millis(config.watch_delay_ms))
+            .map_err(|e| TlsError::Watch(e.to_string()))?;
+        for dir in &dirs {
+            watcher.watch(dir, RecursiveMode::NonRecursive)
+                .map_err(|e| TlsError::Watch(format!("{}: {}", dir.display(), e)))?;
+            log::info!("watching {} for certificate changes", dir.display());
+        }
+
+        let watch_resolver = Arc::clone(&resolver);
+        let watch_thread = thread::Builder::new().name("tls-watch-thread".to_string())
+            .spawn(move || watch_loop(rx, watch_resolver))
+            .map_err(|e| TlsError::Watch(e.to_string()))?;
+
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            provider,
+            resolver,
+            handshake_timeout: Duration::from_secs(config.handshake_timeout),
+            client_verifier,
+            watcher: Mutex::new(Some(watcher)),
+            watch_thread: Mutex::new(Some(watch_thread)),
+        })
+    }
+
+    /// Runs the handshake, bounded by `tls.handshake_timeout`, and checks any client certificate
+    pub async fn accept(&self, stream: TcpStream) -> Result<(TlsStream<TcpStream>, ClientCert), std::io::Error> {
+        let stream = match tokio::time::timeout(self.handshake_timeout, self.acceptor.accept(stream)).await {
+            Ok(s) => s?,
+            Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+        };
+        let client_cert = self.identify(stream.get_ref().1.peer_certificates());
+        Ok((stream, client_cert))
+    }
+
+    /// A config for QUIC listeners with the same certificates and client verification, QUIC only
+    /// runs over TLS 1.3 so `tls.min_version` doesn't apply
+    pub fn quic_config(&self) -> Result<ServerConfig, TlsError> {
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&self.provider))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(TlsError::Rustls)?;
+        let builder = match &self.client_verifier {
+            Some(v) => builder.with_client_cert_verifier(Arc::clone(v) as Arc<_>),
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder.with_cert_resolver(Arc::clone(&self.resolver) as Arc<_>);
+        server_config.alpn_protocols = vec![ALPN_H3.to_vec()];
+        Ok(server_config)
+    }
+
+    /// What the certificate chain a client presented in its handshake says about it
+    pub fn identify(&self, chain: Option<&[CertificateDer<'_>]>) -> ClientCert {
+        match &self.client_verifier {
+            Some(v) => v.identify(chain),
+            None => ClientCert::None,
+        }
+    }
+
+    /// Stops watching the certificate files, the loaded certificates stay in use
+    pub fn close(&self) {
+        drop(self.watcher.lock().unwrap().take());
+        if let Some(t) = self.watch_thread.lock().unwrap().take() {
+            if t.join().is_err() {
+                log::warn!("tls watch thread panicked");
+            }
+        }
+    }
+}
+
+//the directories may hold other files, but reloading is cheap and only a changed pair gets swapped
+fn watch_loop(rx: Receiver<DebouncedEvent>, resolver: Arc<CertResolver>) {
+    while let Ok(event) = rx.recv() {
+        log::debug!("{:?}", &event);
+        match event {
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) | DebouncedEvent::Chmod(_) => {},
+            DebouncedEvent::Error(e, p) => log::warn!("notify error on {:?}: {}", p, e),
+            _ => resolver.reload(),
+        }
+    }
+    log::info!("tls watch loop exiting");
+}
+
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| TlsError::Io(path.to_path_buf(), e))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+pub(crate) fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| TlsError::Io(path.to_path_buf(), e))?);
+    match rustls_pemfile::private_key(&mut reader) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(TlsError::NoKey(path.to_path_buf())),
+        Err(e) => Err(TlsError::Io(path.to_path_buf(), e)),
+    }
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+use crate::config::TlsConfig;
+use super::{load_certs, load_key, TlsError};
+
+//a certificate/key pair from the config, the default pair has no names
+#[derive(Debug)]
+struct CertPair {
+    names: Vec<String>,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// Picks the certificate for a handshake from the SNI name, falling back to the default pair
+///
+/// The loaded keys are swapped as a whole on `reload` so a handshake never sees half an update.
+#[derive(Debug)]
+pub struct CertResolver {
+    provider: Arc<CryptoProvider>,
+    pairs: Vec<CertPair>,
+    //parallel to pairs
+    keys: RwLock<Arc<Vec<Arc<CertifiedKey>>>>,
+}
+
+impl CertResolver {
+    /// Loads every pair, unlike `reload` any failure here is an error
+    pub fn new(config: &TlsConfig, provider: Arc<CryptoProvider>) -> Result<CertResolver, TlsError> {
+        let mut pairs = vec![CertPair { names: Vec::new(), cert: config.cert.clone(), key: config.key.clone() }];
+        pairs.extend(config.certificates.iter().map(|c| CertPair {
+            names: c.names.clone(),
+            cert: c.cert.clone(),
+            key: c.key.clone(),
+        }));
+
+        let keys = pairs.iter()
+            .map(|p| load_pair(p, &provider).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(CertResolver { provider, pairs, keys: RwLock::new(Arc::new(keys)) })
+    }
+
+    /// Reads every pair again, one that fails to load is logged and keeps serving what it had
+    pub fn reload(&self) {
+        let current = Arc::clone(&self.keys.read().unwrap());
+        let keys = self.pairs.iter().zip(current.iter())
+            .map(|(pair, old)| match load_pair(pair, &self.provider) {
+                Ok(new) if new.cert == old.cert => Arc::clone(old),
+                Ok(new) => {
+                    log::info!("loaded new certificate from {}", pair.cert.display());
+                    Arc::new(new)
+                },
+                Err(e) => {
+                    log::error!("keeping the previous certificate for {}, {}", pair.cert.display(), e);
+                    Arc::clone(old)
+                },
+            })
+            .collect::<Vec<_>>();
+
+        *self.keys.write().unwrap() = Arc::new(keys);
+    }
+
+    /// Every file a pair is loaded from
+    pub fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.pairs.iter().flat_map(|p| vec![&p.cert, &p.key])
+    }
+
+    //exact names win over wildcards, anything else gets the default pair
+    fn select(&self, server_name: Option<&str>) -> usize {
+        let name = match server_name {
+            Some(n) => n.trim_end_matches('.').to_ascii_lowercase(),
+            None => return 0,
+        };
+        let exact = self.pairs.iter().position(|p| p.names.contains(&name));
+        let wildcard = || {
+            let parent = name.split_once('.')?.1;
+            self.pairs.iter().position(|p| p.names.iter().any(|n| n.strip_prefix("*.") == Some(parent)))
+        };
+        exact.or_else(wildcard).unwrap_or(0)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let i = self.select(client_hello.server_name());
+        Some(Arc::clone(&self.keys.read().unwrap()[i]))
+    }
+}
+
+fn load_pair(pair: &CertPair, provider: &CryptoProvider) -> Result<CertifiedKey, TlsError> {
+    let certs = load_certs(&pair.cert)?;
+    let key = load_key(&pair.key)?;
+    CertifiedKey::from_der(certs, key, provider).map_err(TlsError::Rustls)
+}
@@ -0,0 +1,321 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rcgen::{BasicConstraints, CertificateParams, CertificateSigningRequestParams, DnType, IsCa, KeyPair};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use webserv::config::{AcmeConfig, Config};
+use webserv::Server;
+
+mod common;
+use common::tls;
+
+//just enough of an ACME CA (RFC 8555) for one order of one name: signatures are checked and the
+//HTTP-01 challenge is fetched from the server under test, nonces and account state are not
+struct FakeCa {
+    root: rcgen::Certificate,
+    root_key: KeyPair,
+    base: String,
+    //the plain HTTP port challenges are fetched from, known once the server under test is up
+    http_port: watch::Receiver<Option<u16>>,
+    state: Mutex<Order>,
+}
+
+#[derive(Default)]
+struct Order {
+    jwk: Option<Value>,
+    name: String,
+    validated: bool,
+    certificate: Option<String>,
+}
+
+static TOKEN: &str = "hjZ1dk9ZJ4-token";
+
+//starts the CA on loopback with a root written to dir/acme-root.pem, returns its directory URL
+async fn fake_ca(dir: &Path, http_port: watch::Receiver<Option<u16>>) -> String {
+    let root_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, "fake acme root");
+    let root = params.self_signed(&root_key).unwrap();
+    fs::write(dir.join("acme-root.pem"), root.pem()).unwrap();
+
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap().signed_by(&key, &root, &root_key).unwrap();
+    let tls = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.der().clone()], rustls::pki_types::PrivateKeyDer::Pkcs8(key.serialize_der().into()))
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(tls));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("https://127.0.0.1:{}", listener.local_addr().unwrap().port());
+    let ca = Arc::new(FakeCa { root, root_key, base: base.clone(), http_port, state: Mutex::new(Order::default()) });
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (acceptor, ca) = (acceptor.clone(), Arc::clone(&ca));
+            tokio::spawn(async move {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let (method, path, body) = read_request(&mut stream).await;
+                    let (status, headers, body) = ca.handle(&method, &path, &body).await;
+                    let mut response = format!("HTTP/1.1 {}\r\nReplay-Nonce: nonce\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+                    for (name, value) in headers {
+                        response.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    response.push_str("\r\n");
+                    response.push_str(&body);
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+        }
+    });
+    format!("{}/dir", base)
+}
+
+impl FakeCa {
+    async fn handle(&self, method: &str, path: &str, body: &[u8]) -> (&'static str, Vec<(&'static str, String)>, String) {
+        let base = &self.base;
+        if method == "GET" && path == "/dir" {
+            let directory = json!({
+                "newNonce": format!("{}/nonce", base),
+                "newAccount": format!("{}/account", base),
+                "newOrder": format!("{}/order", base),
+            });
+            return ("200 OK", Vec::new(), directory.to_string());
+        }
+        if method == "HEAD" {
+            return ("200 OK", Vec::new(), String::new());
+        }
+
+        let payload = match self.verify(body) {
+            Some(p) => p,
+            None => return ("400 Bad Request", Vec::new(), json!({ "type": "urn:ietf:params:acme:error:malformed" }).to_string()),
+        };
+        let location = |p: &str| vec![("Location", format!("{}{}", base, p))];
+        match path {
+            "/account" => ("201 Created", location("/account/1"), json!({ "status": "valid" }).to_string()),
+            "/order" => {
+                self.state.lock().unwrap().name = payload["identifiers"][0]["value"].as_str().unwrap().to_string();
+                ("201 Created", location("/order/1"), self.order())
+            },
+            "/order/1" => ("200 OK", Vec::new(), self.order()),
+            "/authz/1" => ("200 OK", Vec::new(), self.authorization()),
+            "/challenge/1" => {
+                self.validate().await;
+                ("200 OK", Vec::new(), json!({ "type": "http-01", "status": "processing" }).to_string())
+            },
+            "/finalize/1" => {
+                let csr = URL_SAFE_NO_PAD.decode(payload["csr"].as_str().unwrap()).unwrap();
+                let csr = CertificateSigningRequestParams::from_der(&csr.into()).unwrap();
+                let leaf = csr.signed_by(&self.root, &self.root_key).unwrap();
+                self.state.lock().unwrap().certificate = Some(format!("{}{}", leaf.pem(), self.root.pem()));
+                ("200 OK", Vec::new(), self.order())
+            },
+            "/cert/1" => ("200 OK", Vec::new(), self.state.lock().unwrap().certificate.clone().unwrap()),
+            _ => ("404 Not Found", Vec::new(), String::new()),
+        }
+    }
+
+    //checks the JWS against the account key (the one embedded for newAccount) and returns the payload
+    fn verify(&self, body: &[u8]) -> Option<Value> {
+        let jws: Value = serde_json::from_slice(body).ok()?;
+        let field = |f: &str| jws[f].as_str().map(str::to_string);
+        let (protected, payload, signature) = (field("protected")?, field("payload")?, field("signature")?);
+        let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(&protected).ok()?).ok()?;
+        assert_eq!(header["alg"], "ES256");
+
+        let mut state = self.state.lock().unwrap();
+        if header.get("jwk").is_some() {
+            state.jwk = Some(header["jwk"].clone());
+        }
+        let jwk = state.jwk.clone()?;
+        let mut point = vec![4];
+        point.extend(URL_SAFE_NO_PAD.decode(jwk["x"].as_str()?).ok()?);
+        point.extend(URL_SAFE_NO_PAD.decode(jwk["y"].as_str()?).ok()?);
+        let key = ring::signature::UnparsedPublicKey::new(&ring::signature::ECDSA_P256_SHA256_FIXED, point);
+        key.verify(format!("{}.{}", protected, payload).as_bytes(), &URL_SAFE_NO_PAD.decode(&signature).ok()?).ok()?;
+
+        match payload.as_str() {
+            "" => Some(Value::Null),
+            p => serde_json::from_slice(&URL_SAFE_NO_PAD.decode(p).ok()?).ok(),
+        }
+    }
+
+    fn order(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let status = match (&state.certificate, state.validated) {
+            (Some(_), _) => "valid",
+            (None, true) => "ready",
+            (None, false) => "pending",
+        };
+        json!({
+            "status": status,
+            "authorizations": [format!("{}/authz/1", self.base)],
+            "finalize": format!("{}/finalize/1", self.base),
+            "certificate": format!("{}/cert/1", self.base),
+        }).to_string()
+    }
+
+    fn authorization(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let status = if state.validated { "valid" } else { "pending" };
+        json!({
+            "status": status,
+            "identifier": { "type": "dns", "value": state.name },
+            "challenges": [
+                { "type": "dns-01", "url": format!("{}/challenge/2", self.base), "token": "unused", "status": "pending" },
+                { "type": "http-01", "url": format!("{}/challenge/1", self.base), "token": TOKEN, "status": status },
+            ],
+        }).to_string()
+    }
+
+    //RFC 8555 8.3: the body has to be the token and the account key's thumbprint
+    async fn validate(&self) {
+        let mut http_port = self.http_port.clone();
+        let port = loop {
+            if let Some(p) = *http_port.borrow() {
+                break p;
+            }
+            http_port.changed().await.unwrap();
+        };
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("GET /.well-known/acme-challenge/{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", TOKEN);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let jwk = self.state.lock().unwrap().jwk.clone().unwrap();
+        let thumbprint = ring::digest::digest(&ring::digest::SHA256, jwk.to_string().as_bytes());
+        let expected = format!("\r\n\r\n{}.{}", TOKEN, URL_SAFE_NO_PAD.encode(thumbprint));
+        self.state.lock().unwrap().validated = response.starts_with("HTTP/1.1 200 ") && response.ends_with(&expected);
+    }
+}
+
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> (String, String, Vec<u8>) {
+    let mut buf = Vec::new();
+    let mut byte = [0u8; 1];
+    while !buf.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).await.unwrap();
+        buf.push(byte[0]);
+    }
+    let head = String::from_utf8(buf).unwrap();
+    let mut request_line = head.split(' ');
+    let (method, path) = (request_line.next().unwrap().to_string(), request_line.next().unwrap().to_string());
+    let length = head.lines()
+        .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.unwrap();
+    (method, path, body)
+}
+
+fn acme_config(dir: &Path, directory: String) -> Config {
+    let mut config = common::tls_config(&dir.join("live"));
+    config.server.listen = vec!["127.0.0.1:0".parse().unwrap()];
+    tls(&mut config).acme = Some(AcmeConfig {
+        directory,
+        names: vec!["localhost".to_string()],
+        contact: vec!["mailto:ops@example.com".to_string()],
+        state_dir: dir.join("acme"),
+        renew_before_days: 30,
+        retry_secs: 1,
+        directory_ca: Some(dir.join("acme-root.pem")),
+    });
+    config
+}
+
+//None until the handshake succeeds with only `root` trusted
+async fn get_trusting(root: &Path, addr: SocketAddr) -> Option<String> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut &fs::read(root).unwrap()[..]) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tcp = TcpStream::connect(addr).await.ok()?;
+    let mut stream = TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost").unwrap(), tcp).await.ok()?;
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.ok()?;
+    Some(response)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn issues_a_certificate_over_http_01() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("live")).unwrap();
+    fs::write(dir.path().join("index.html"), "issued").unwrap();
+    let (http_port, ca_http_port) = watch::channel(None);
+    let directory = fake_ca(dir.path(), ca_http_port).await;
+
+    let server = Server::builder(acme_config(dir.path(), directory)).doc_root(dir.path()).build().unwrap().start().await.unwrap();
+    http_port.send(Some(server.local_addrs()[0].port())).unwrap();
+
+    //a self-signed placeholder is served until the CA has issued
+    let root = dir.path().join("acme-root.pem");
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let response = loop {
+        if let Some(r) = get_trusting(&root, server.tls_addrs()[0]).await {
+            break r;
+        }
+        assert!(Instant::now() < deadline, "certificate was never issued");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nissued"), "{}", response);
+
+    //the account is kept for the next run and no staged files are left next to the pair
+    assert!(dir.path().join("acme/account.key").is_file());
+    let files = fs::read_dir(dir.path().join("live")).unwrap().map(|e| e.unwrap().file_name()).collect::<Vec<_>>();
+    assert_eq!(files.len(), 2, "{:?}", files);
+
+    server.shutdown().await.unwrap();
+}
+
+type ConfigChange = fn(&mut Config);
+
+#[test]
+fn acme_config_is_validated() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("acme-root.pem"), "").unwrap();
+    let cases: [(&str, ConfigChange); 5] = [
+        ("plain http directory", |c| acme(c).directory = "http://127.0.0.1/dir".to_string()),
+        ("wildcard name", |c| acme(c).names = vec!["*.example.com".to_string()]),
+        ("no names", |c| acme(c).names.clear()),
+        ("no plain listener", |c| c.server.listen.clear()),
+        ("missing cert directory", |c| tls(c).cert = PathBuf::from("/nonexistent/cert.pem")),
+    ];
+    fs::create_dir(dir.path().join("live")).unwrap();
+    let mut valid = acme_config(dir.path(), "https://127.0.0.1/dir".to_string());
+    valid.server.doc_root = dir.path().to_path_buf();
+    assert!(valid.validate().is_ok(), "{:?}", valid.validate());
+
+    for (case, change) in cases {
+        let mut config = valid.clone();
+        change(&mut config);
+        assert!(config.validate().is_err(), "{} was accepted", case);
+    }
+}
+
+fn acme(config: &mut Config) -> &mut AcmeConfig {
+    tls(config).acme.as_mut().unwrap()
+}
@@ -0,0 +1,93 @@
+//every test crate builds its own copy of this module and only uses some of it
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use rustls::pki_types::CertificateDer;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use webserv::config::{Config, TlsConfig, TlsVersion};
+use webserv::{Server, ServerHandle};
+
+/// HTTPS only on an ephemeral port with `cert.pem`/`key.pem` from dir, every optional `[tls]`
+/// section left out for the tests to fill in
+pub fn tls_config(dir: &Path) -> Config {
+    let mut config = Config::default();
+    config.server.listen.clear();
+    config.tls = Some(TlsConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+        min_version: TlsVersion::Tls12,
+        handshake_timeout: 10,
+        certificates: Vec::new(),
+        watch_delay_ms: 50,
+        client_auth: None,
+        redirect: None,
+        hsts: None,
+        acme: None,
+        http3: None,
+    });
+    config
+}
+
+pub fn tls(config: &mut Config) -> &mut TlsConfig {
+    config.tls.as_mut().unwrap()
+}
+
+/// A self-signed certificate for localhost written into dir, returned in DER for the client to trust
+pub fn self_signed(dir: &Path) -> CertificateDer<'static> {
+    self_signed_pair(dir, "", &["localhost"])
+}
+
+/// Writes `<prefix>cert.pem` and `<prefix>key.pem`
+pub fn self_signed_pair(dir: &Path, prefix: &str, names: &[&str]) -> CertificateDer<'static> {
+    let generated = rcgen::generate_simple_self_signed(names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+    //written elsewhere and renamed into place, the way renewal tools replace them
+    let staged = dir.join(".staged");
+    fs::write(&staged, generated.cert.pem()).unwrap();
+    fs::rename(&staged, dir.join(format!("{}cert.pem", prefix))).unwrap();
+    fs::write(&staged, generated.key_pair.serialize_pem()).unwrap();
+    fs::rename(&staged, dir.join(format!("{}key.pem", prefix))).unwrap();
+    generated.cert.der().clone()
+}
+
+pub async fn start(config: Config, doc_root: &Path) -> Result<ServerHandle, std::io::Error> {
+    Server::builder(config).doc_root(doc_root).build().unwrap().start().await
+}
+
+/// Plain HTTP on an ephemeral port, everything else from config
+pub async fn serve(config: Config) -> ServerHandle {
+    Server::builder(config).listen("127.0.0.1:0".parse().unwrap()).build().unwrap().start().await.unwrap()
+}
+
+/// Plain HTTP on an ephemeral port serving doc_root with the default config
+pub async fn serve_dir(doc_root: &Path) -> ServerHandle {
+    let mut config = Config::default();
+    config.server.doc_root = doc_root.to_path_buf();
+    serve(config).await
+}
+
+/// Writes head as is and reads until the server closes the connection
+pub async fn request(addr: SocketAddr, head: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(head.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+pub async fn get(addr: SocketAddr, path: &str) -> String {
+    get_host(addr, "localhost", path).await
+}
+
+pub async fn get_host(addr: SocketAddr, host: &str, path: &str) -> String {
+    request(addr, &format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host)).await
+}
+
+pub fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map(|(_, b)| b).unwrap_or_default()
+}
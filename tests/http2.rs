@@ -0,0 +1,211 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::future::{poll_fn, Future};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::task::Poll;
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_rustls::TlsConnector;
+
+use webserv::config::Config;
+
+mod common;
+use common::{self_signed, start};
+
+fn h2c_config() -> Config {
+    let mut config = Config::default();
+    config.server.listen = vec!["127.0.0.1:0".parse().unwrap()];
+    config.http2.h2c = true;
+    config
+}
+
+//the client half of an HTTP/2 connection, with the server's SETTINGS_MAX_CONCURRENT_STREAMS
+//published once the connection task has seen it
+async fn handshake<S>(io: S) -> (SendRequest<Bytes>, watch::Receiver<usize>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, mut connection) = h2::client::handshake(io).await.unwrap();
+    let (limit, limit_rx) = watch::channel(0);
+    tokio::spawn(poll_fn(move |cx| {
+        let done = std::pin::Pin::new(&mut connection).poll(cx);
+        limit.send_if_modified(|l| {
+            let seen = connection.max_concurrent_send_streams();
+            std::mem::replace(l, seen) != seen
+        });
+        match done {
+            Poll::Ready(_) => Poll::Ready(()),
+            Poll::Pending => Poll::Pending,
+        }
+    }));
+    (client, limit_rx)
+}
+
+async fn get(client: &SendRequest<Bytes>, path: &str) -> (http::response::Parts, Vec<u8>) {
+    let request = http::Request::get(format!("http://localhost{}", path)).body(()).unwrap();
+    let mut client = client.clone().ready().await.unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let (parts, mut body) = response.await.unwrap().into_parts();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.unwrap();
+        body.flow_control().release_capacity(chunk.len()).unwrap();
+        data.extend_from_slice(&chunk);
+    }
+    (parts, data)
+}
+
+#[tokio::test]
+async fn h2c_prior_knowledge_multiplexes_streams() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("index.html"), "index").unwrap();
+    //bigger than the default windows so flow control has to kick in
+    let big = vec![b'x'; 300_000];
+    fs::write(dir.path().join("big.bin"), &big).unwrap();
+
+    let mut config = h2c_config();
+    config.http2.max_concurrent_streams = 7;
+    let server = start(config, dir.path()).await.unwrap();
+    let addr = server.local_addrs()[0];
+
+    let (client, mut limit) = handshake(TcpStream::connect(addr).await.unwrap()).await;
+    //streams opened before the server's SETTINGS arrive could go past the limit and be refused
+    assert_eq!(get(&client, "/").await.0.status, 200);
+    limit.wait_for(|l| *l != 0).await.unwrap();
+    assert_eq!(*limit.borrow(), 7);
+
+    let requests = (0..10).map(|i| {
+        let client = client.clone();
+        async move { get(&client, if i % 2 == 0 { "/big.bin" } else { "/" }).await }
+    });
+    let responses = futures::future::join_all(requests).await;
+    for (i, (parts, body)) in responses.into_iter().enumerate() {
+        assert_eq!(parts.status, 200);
+        assert!(parts.headers.get("connection").is_none());
+        if i % 2 == 0 {
+            assert_eq!(body.len(), big.len());
+            assert_eq!(parts.headers["content-length"], big.len().to_string());
+        } else {
+            assert_eq!(body, b"index");
+        }
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn h2c_listener_still_speaks_http_1_1() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("index.html"), "old school").unwrap();
+    let server = start(h2c_config(), dir.path()).await.unwrap();
+
+    let mut stream = TcpStream::connect(server.local_addrs()[0]).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nold school"), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn head_and_errors_over_h2() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("index.html"), "index").unwrap();
+    let server = start(h2c_config(), dir.path()).await.unwrap();
+    let (client, _) = handshake(TcpStream::connect(server.local_addrs()[0]).await.unwrap()).await;
+
+    let (parts, body) = get(&client, "/missing").await;
+    assert_eq!(parts.status, 404);
+    assert!(!body.is_empty());
+    let (parts, body) = get(&client, "/index.html?v=2").await;
+    assert_eq!(parts.status, 200);
+    assert_eq!(body, b"index");
+
+    let request = http::Request::head("http://localhost/").body(()).unwrap();
+    let mut sender = client.clone().ready().await.unwrap();
+    let (response, _) = sender.send_request(request, true).unwrap();
+    let response = response.await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-length"], "5");
+    assert!(response.body().is_end_stream());
+
+    server.shutdown().await.unwrap();
+}
+
+fn tls_config(dir: &Path, http2: bool) -> Config {
+    let mut config = common::tls_config(dir);
+    config.http2.enabled = http2;
+    config
+}
+
+fn connector(trusted: CertificateDer<'static>) -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add(trusted).unwrap();
+    let mut config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn connect_tls(connector: &TlsConnector, addr: SocketAddr) -> tokio_rustls::client::TlsStream<TcpStream> {
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    connector.connect(ServerName::try_from("localhost").unwrap(), tcp).await.unwrap()
+}
+
+#[tokio::test]
+async fn negotiates_h2_over_tls() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = self_signed(dir.path());
+    fs::write(dir.path().join("index.html"), "secure").unwrap();
+    let connector = connector(cert);
+
+    let server = start(tls_config(dir.path(), true), dir.path()).await.unwrap();
+    let stream = connect_tls(&connector, server.tls_addrs()[0]).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    let (client, _) = handshake(stream).await;
+    let (parts, body) = get(&client, "/").await;
+    assert_eq!(parts.status, 200);
+    assert_eq!(parts.headers["x-content-type-options"], "nosniff");
+    assert_eq!(body, b"secure");
+    server.shutdown().await.unwrap();
+
+    //with http2 off the server doesn't offer it and clients fall back to HTTP/1.1
+    let server = start(tls_config(dir.path(), false), dir.path()).await.unwrap();
+    let stream = connect_tls(&connector, server.tls_addrs()[0]).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+    server.shutdown().await.unwrap();
+}
+
+type ConfigChange = fn(&mut Config);
+
+#[test]
+fn http2_config_is_validated() {
+    let dir = tempfile::tempdir().unwrap();
+    let cases: [(&str, ConfigChange); 3] = [
+        ("max_concurrent_streams", |c| c.http2.max_concurrent_streams = 0),
+        ("initial_window_size", |c| c.http2.initial_window_size = 1 << 31),
+        ("max_frame_size", |c| c.http2.max_frame_size = 1024),
+    ];
+    for (key, change) in cases.iter() {
+        let mut config = Config::default();
+        config.server.doc_root = dir.path().to_path_buf();
+        assert!(config.validate().is_ok());
+        change(&mut config);
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains(key), "{}: {}", key, error);
+    }
+}
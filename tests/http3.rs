@@ -0,0 +1,190 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes};
+use h3::client::SendRequest;
+use quinn::crypto::rustls::QuicClientConfig;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use webserv::config::{Config, Http3Config};
+use webserv::Shutdown;
+
+mod common;
+use common::{self_signed, start, tls};
+
+type Client = SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+fn http3_config(dir: &Path) -> Config {
+    let mut config = common::tls_config(dir);
+    tls(&mut config).http3 = Some(Http3Config {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        alt_svc_port: None,
+        alt_svc_max_age: 3600,
+        max_concurrent_streams: 10,
+        idle_timeout: 30,
+    });
+    config
+}
+
+fn client_config(trusted: CertificateDer<'static>, alpn: &[u8]) -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    roots.add(trusted).unwrap();
+    let mut config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+    config
+}
+
+//the client endpoint has to outlive the connection, so it is handed back too
+async fn connect(trusted: CertificateDer<'static>, addr: SocketAddr) -> (quinn::Endpoint, Client) {
+    let crypto = QuicClientConfig::try_from(client_config(trusted, b"h3")).unwrap();
+    let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+    let connection = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+
+    let (mut driver, client) = h3::client::new(h3_quinn::Connection::new(connection)).await.unwrap();
+    tokio::spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+    (endpoint, client)
+}
+
+async fn request(client: &Client, method: http::Method, path: &str) -> (http::Response<()>, Vec<u8>) {
+    let request = http::Request::builder()
+        .method(method)
+        .uri(format!("https://localhost{}", path))
+        .body(())
+        .unwrap();
+    let mut stream = client.clone().send_request(request).await.unwrap();
+    stream.finish().await.unwrap();
+    let response = stream.recv_response().await.unwrap();
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+        while chunk.has_remaining() {
+            let part = chunk.chunk();
+            body.extend_from_slice(part);
+            let n = part.len();
+            chunk.advance(n);
+        }
+    }
+    (response, body)
+}
+
+#[tokio::test]
+async fn serves_over_http3() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = self_signed(dir.path());
+    fs::write(dir.path().join("index.html"), "quic").unwrap();
+    //several flow control windows' worth, streamed from disk
+    let big = (0..2_000_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(dir.path().join("big.bin"), &big).unwrap();
+
+    let server = start(http3_config(dir.path()), dir.path()).await.unwrap();
+    let (_endpoint, client) = connect(cert, server.http3_addrs()[0]).await;
+
+    let requests = (0..6).map(|i| {
+        let client = client.clone();
+        async move { request(&client, http::Method::GET, if i % 2 == 0 { "/big.bin" } else { "/" }).await }
+    });
+    for (i, (response, body)) in futures::future::join_all(requests).await.into_iter().enumerate() {
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get("connection").is_none());
+        match i % 2 {
+            0 => assert!(body == big, "big.bin came back {} bytes long", body.len()),
+            _ => assert_eq!(body, b"quic"),
+        }
+    }
+
+    let (response, body) = request(&client, http::Method::HEAD, "/").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-length"], "4");
+    assert!(body.is_empty());
+    let (response, _) = request(&client, http::Method::GET, "/missing").await;
+    assert_eq!(response.status(), 404);
+
+    assert_eq!(server.shutdown().await.unwrap(), Shutdown::Drained);
+}
+
+#[tokio::test]
+async fn advertised_with_alt_svc_over_https() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = self_signed(dir.path());
+    fs::write(dir.path().join("index.html"), "tcp").unwrap();
+
+    let mut config = http3_config(dir.path());
+    config.server.listen = vec!["127.0.0.1:0".parse().unwrap()];
+    let server = start(config, dir.path()).await.unwrap();
+    let alt_svc = format!("Alt-Svc: h3=\":{}\"; ma=3600\r\n", server.http3_addrs()[0].port());
+
+    let connector = TlsConnector::from(Arc::new(client_config(cert, b"http/1.1")));
+    let tcp = TcpStream::connect(server.tls_addrs()[0]).await.unwrap();
+    let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), tcp).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.contains(&alt_svc), "{}", response);
+
+    //plain HTTP isn't pointed at it, clients only trust the advertisement from an authenticated origin
+    let mut stream = TcpStream::connect(server.local_addrs()[0]).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(!response.contains("Alt-Svc"), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_closes_idle_http3_connections() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = self_signed(dir.path());
+    fs::write(dir.path().join("index.html"), "bye").unwrap();
+
+    let mut config = http3_config(dir.path());
+    config.server.shutdown_grace = 10;
+    let server = start(config, dir.path()).await.unwrap();
+    let (_endpoint, client) = connect(cert, server.http3_addrs()[0]).await;
+    assert_eq!(request(&client, http::Method::GET, "/").await.1, b"bye");
+    assert_eq!(server.connections(), 1);
+
+    let started = Instant::now();
+    assert_eq!(server.shutdown().await.unwrap(), Shutdown::Drained);
+    assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+}
+
+type ConfigChange = fn(&mut Config);
+
+#[test]
+fn http3_config_is_validated() {
+    let dir = tempfile::tempdir().unwrap();
+    self_signed(dir.path());
+    let mut valid = http3_config(dir.path());
+    valid.server.doc_root = dir.path().to_path_buf();
+    assert!(valid.validate().is_ok(), "{:?}", valid.validate());
+
+    let cases: [(&str, ConfigChange); 3] = [
+        ("no listen address", |c| http3(c).listen.clear()),
+        ("no streams", |c| http3(c).max_concurrent_streams = 0),
+        ("no idle timeout", |c| http3(c).idle_timeout = 0),
+    ];
+    for (case, change) in cases {
+        let mut config = valid.clone();
+        change(&mut config);
+        assert!(config.validate().is_err(), "{} was accepted", case);
+    }
+}
+
+fn http3(config: &mut Config) -> &mut Http3Config {
+    tls(config).http3.as_mut().unwrap()
+}
@@ -0,0 +1,507 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use webserv::config::Config;
+use webserv::{Server, Shutdown};
+
+mod common;
+use common::{get, serve, serve_dir};
+
+#[tokio::test]
+async fn instances_serve_their_own_doc_roots() {
+    let roots = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    fs::write(roots[0].path().join("index.html"), "first").unwrap();
+    fs::write(roots[1].path().join("index.html"), "second").unwrap();
+
+    let first = serve_dir(roots[0].path()).await;
+    let second = serve_dir(roots[1].path()).await;
+    assert_ne!(first.local_addr(), second.local_addr());
+
+    let response = get(first.local_addr(), "/").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nfirst"), "{}", response);
+    assert!(get(second.local_addr(), "/index.html").await.ends_with("\r\n\r\nsecond"));
+
+    assert_eq!(first.shutdown().await.unwrap(), Shutdown::Drained);
+    assert_eq!(second.shutdown().await.unwrap(), Shutdown::Drained);
+}
+
+#[tokio::test]
+async fn missing_file_gets_404() {
+    let root = tempfile::tempdir().unwrap();
+    let server = serve_dir(root.path()).await;
+
+    let response = get(server.local_addr(), "/nope.html").await;
+    assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn query_is_ignored_and_escapes_decoded() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("style.css"), "p{}").unwrap();
+    fs::write(root.path().join("two words.txt"), "spaced").unwrap();
+    let server = serve_dir(root.path()).await;
+    let addr = server.local_addr();
+
+    assert!(get(addr, "/style.css?v=2").await.ends_with("\r\n\r\np{}"));
+    assert!(get(addr, "/st%79le.css?v=%zz").await.ends_with("\r\n\r\np{}"));
+    assert!(get(addr, "/two%20words.txt").await.ends_with("\r\n\r\nspaced"));
+    for bad in ["/style.css%", "/style.css%2", "/%zzstyle.css", "/style.css%00", "/%ff"].iter() {
+        let response = get(addr, bad).await;
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}: {}", bad, response);
+    }
+    //an escaped ../ is caught by the doc root check like a literal one
+    let response = get(addr, "/%2e%2e%2f%2e%2e%2fetc%2fpasswd").await;
+    assert!(!response.starts_with("HTTP/1.1 200 "), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+//the header lines of a response head that don't depend on when or on which connection it was sent
+fn stable_headers(head: &str) -> Vec<&str> {
+    head.lines().skip(1).filter(|l| !l.starts_with("Date: ") && !l.starts_with("Connection: ")).collect()
+}
+
+#[tokio::test]
+async fn head_matches_get_without_a_body() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("index.html"), "<p>hello</p>").unwrap();
+    let server = serve_dir(root.path()).await;
+    let addr = server.local_addr();
+
+    let get = get(addr, "/index.html").await;
+    let (get_head, get_body) = get.split_once("\r\n\r\n").unwrap();
+    assert_eq!(get_body, "<p>hello</p>");
+
+    //pipelined, so any body bytes sent for a HEAD would show up in front of the next response
+    let responses = common::request(
+        addr,
+        "HEAD /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         HEAD /missing.html HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    let (head, rest) = responses.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert!(head.lines().any(|l| l == "Content-Length: 12"), "{}", head);
+    assert_eq!(stable_headers(head), stable_headers(get_head));
+
+    //errors describe the page GET would have had, without sending it either
+    assert!(rest.starts_with("HTTP/1.1 404 Not found\r\n"), "{}", rest);
+    let (missing, rest) = rest.split_once("\r\n\r\n").unwrap();
+    let length = missing.lines().find_map(|l| l.strip_prefix("Content-Length: ")).unwrap();
+    assert_ne!(length, "0", "{}", missing);
+
+    assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"), "{}", rest);
+    assert!(rest.ends_with("\r\n\r\n<p>hello</p>"), "{}", rest);
+
+    server.shutdown().await.unwrap();
+}
+
+async fn method(addr: SocketAddr, method: &str, target: &str) -> String {
+    common::request(addr, &format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", method, target)).await
+}
+
+#[tokio::test]
+async fn options_and_unserved_methods_say_what_is_allowed() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("index.html"), "hi").unwrap();
+    let server = serve_dir(root.path()).await;
+    let addr = server.local_addr();
+    let allow = "\r\nAllow: GET, HEAD, OPTIONS\r\n";
+
+    for target in ["*", "/", "/index.html"] {
+        let response = method(addr, "OPTIONS", target).await;
+        assert!(response.starts_with("HTTP/1.1 204 No content\r\n"), "{}: {}", target, response);
+        assert!(response.contains(allow), "{}: {}", target, response);
+        assert!(response.ends_with("\r\n\r\n"), "{}: {}", target, response);
+    }
+    let response = method(addr, "OPTIONS", "/missing.html").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not found\r\n"), "{}", response);
+
+    //known methods we don't serve on static files
+    for name in ["POST", "PUT", "DELETE", "PATCH"] {
+        let response = method(addr, name, "/index.html").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method not allowed\r\n"), "{}: {}", name, response);
+        assert!(response.contains(allow), "{}: {}", name, response);
+    }
+    //methods are case-sensitive, so `get` is one we have never heard of
+    for name in ["BREW", "PROPFIND", "get"] {
+        let response = method(addr, name, "/index.html").await;
+        assert!(response.starts_with("HTTP/1.1 501 Not implemented\r\n"), "{}: {}", name, response);
+        assert!(response.contains(allow), "{}: {}", name, response);
+    }
+    let response = method(addr, "GE(T", "/index.html").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad request\r\n"), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+//reads exactly one response off a connection that may stay open, by its Content-Length
+async fn read_response(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let text = String::from_utf8_lossy(&response).into_owned();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let len = head.lines().find_map(|l| l.strip_prefix("Content-Length: ")).map_or(0, |l| l.parse().unwrap());
+            if body.len() >= len {
+                return text;
+            }
+        }
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "connection closed part way through {}", text);
+        response.extend_from_slice(&buf[..n]);
+    }
+}
+
+//whether the server closes the connection of its own accord, well before the idle timeout
+async fn closes(stream: &mut TcpStream) -> bool {
+    let mut buf = [0u8; 64];
+    matches!(tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await, Ok(Ok(0)))
+}
+
+#[tokio::test]
+async fn connection_persistence_follows_the_version_and_connection_header() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("index.html"), "hi").unwrap();
+    let mut config = Config::default();
+    config.server.doc_root = root.path().to_path_buf();
+    config.http.keep_alive_timeout = 30;
+    let server = serve(config).await;
+
+    let cases = [
+        ("GET / HTTP/1.0\r\n\r\n", false),
+        ("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", true),
+        ("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", true),
+        ("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", true),
+        ("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", false),
+        ("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: foo, close\r\n\r\n", false),
+    ];
+    for (request, persistent) in cases.iter() {
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut stream).await;
+        assert!(response.ends_with("\r\n\r\nhi"), "{:?}: {}", request, response);
+        let token = if *persistent { "keep-alive" } else { "close" };
+        assert!(response.contains(&format!("\r\nConnection: {}\r\n", token)), "{:?}: {}", request, response);
+
+        if *persistent {
+            //still there for another request
+            stream.write_all(request.as_bytes()).await.unwrap();
+            assert!(read_response(&mut stream).await.ends_with("\r\n\r\nhi"), "{:?}", request);
+        } else {
+            assert!(closes(&mut stream).await, "{:?} left the connection open", request);
+        }
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn connection_closes_after_max_requests() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("index.html"), "hi").unwrap();
+    let mut config = Config::default();
+    config.server.doc_root = root.path().to_path_buf();
+    config.http.max_requests = 3;
+    let server = serve(config).await;
+
+    //one more than the limit, all of them asking to keep the connection open
+    let request = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".repeat(4);
+    let responses = common::request(server.local_addr(), &request).await;
+    let tokens = responses
+        .lines()
+        .filter_map(|l| l.strip_prefix("Connection: "))
+        .collect::<Vec<_>>();
+    assert_eq!(tokens, ["keep-alive", "keep-alive", "close"], "{}", responses);
+    assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 3);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn idle_connections_are_closed_after_the_keep_alive_timeout() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("index.html"), "hi").unwrap();
+    let mut config = Config::default();
+    config.server.doc_root = root.path().to_path_buf();
+    config.http.keep_alive_timeout = 1;
+    let server = serve(config).await;
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    assert!(read_response(&mut stream).await.contains("\r\nConnection: keep-alive\r\n"));
+    let idle = Instant::now();
+    assert!(closes(&mut stream).await);
+    assert!(idle.elapsed() >= Duration::from_millis(900), "{:?}", idle.elapsed());
+
+    //a connection that never sends anything at all gets the same treatment
+    let mut silent = TcpStream::connect(server.local_addr()).await.unwrap();
+    assert!(closes(&mut silent).await);
+
+    server.shutdown().await.unwrap();
+}
+
+async fn get_bytes(addr: SocketAddr, path: &str, extra: &str) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", path, extra);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    (String::from_utf8(response[..end].to_vec()).unwrap(), response[end + 4..].to_vec())
+}
+
+#[tokio::test]
+async fn binary_files_are_served_byte_for_byte() {
+    let root = tempfile::tempdir().unwrap();
+    //not valid UTF-8 anywhere, with NULs, a lone continuation byte and a CRLFCRLF in the middle
+    let mut contents = vec![0x00, 0xff, 0xfe, 0x80, b'\r', b'\n', b'\r', b'\n', 0x00, 0x00, 0xc3, 0x28];
+    contents.extend((0..=255u8).cycle().take(70_000));
+    fs::write(root.path().join("blob.bin"), &contents).unwrap();
+    let server = serve_dir(root.path()).await;
+
+    let (head, body) = get_bytes(server.local_addr(), "/blob.bin", "").await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert!(head.contains(&format!("\r\nContent-Length: {}\r\n", contents.len())), "{}", head);
+    assert!(body == contents, "body differs from the file");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn content_type_and_nosniff_headers() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("style.css"), "p{}").unwrap();
+    fs::write(root.path().join("logo.png"), [0x89, b'P', b'N', b'G']).unwrap();
+    let server = serve_dir(root.path()).await;
+    let addr = server.local_addr();
+
+    let response = get(addr, "/style.css").await;
+    assert!(response.contains("\r\nContent-Type: text/css; charset=utf-8\r\n"), "{}", response);
+    assert!(response.contains("\r\nX-Content-Type-Options: nosniff\r\n"), "{}", response);
+    let response = common::request(addr, "HEAD /logo.png HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+    assert!(response.contains("\r\nContent-Type: image/png\r\n"), "{}", response);
+    //error responses are covered too
+    let response = get(addr, "/missing.css").await;
+    assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+    assert!(response.contains("\r\nX-Content-Type-Options: nosniff\r\n"), "{}", response);
+    server.shutdown().await.unwrap();
+
+    let mut config = Config::default();
+    config.server.doc_root = root.path().to_path_buf();
+    config.mime.nosniff = false;
+    let server = serve(config).await;
+    let response = get(server.local_addr(), "/style.css").await;
+    assert!(!response.contains("X-Content-Type-Options"), "{}", response);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn files_over_max_file_size_are_streamed_whole_and_in_ranges() {
+    let root = tempfile::tempdir().unwrap();
+    let contents = (0..300_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(root.path().join("big.bin"), &contents).unwrap();
+    let mut config = Config::default();
+    config.server.doc_root = root.path().to_path_buf();
+    config.cache.max_file_size = 1024;
+    let server = serve(config).await;
+    let addr = server.local_addr();
+
+    let (head, body) = get_bytes(addr, "/big.bin", "").await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert!(head.lines().any(|l| l == "Content-Length: 300000"), "{}", head);
+    assert!(body == contents, "streamed body differs from the file");
+
+    //spans on both sides of the stream chunk size, each read from its own offset
+    let (head, body) = get_bytes(addr, "/big.bin", "Range: bytes=10-19, 70000-200000, -5\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 206 Partial content\r\n"), "{}", head);
+    let boundary = head.lines().find_map(|l| l.strip_prefix("Content-Type: multipart/byteranges; boundary=")).unwrap();
+    let length = head.lines().find_map(|l| l.strip_prefix("Content-Length: ")).unwrap();
+    assert_eq!(length.parse::<usize>().unwrap(), body.len());
+
+    let mut expected = Vec::new();
+    for (start, end) in [(10, 19), (70_000, 200_000), (299_995, 299_999)] {
+        let header = format!(
+            "\r\n--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}-{}/300000\r\n\r\n",
+            boundary, start, end
+        );
+        expected.extend_from_slice(header.as_bytes());
+        expected.extend_from_slice(&contents[start..=end]);
+    }
+    expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    assert!(body == expected, "multipart body differs");
+
+    server.shutdown().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn doc_root_symlink_can_be_repointed() {
+    let dir = tempfile::tempdir().unwrap();
+    for (release, text) in [("v1", "one"), ("v2", "two")] {
+        fs::create_dir(dir.path().join(release)).unwrap();
+        fs::write(dir.path().join(release).join("index.html"), text).unwrap();
+    }
+    let current = dir.path().join("current");
+    std::os::unix::fs::symlink("v1", &current).unwrap();
+    let server = serve_dir(&current).await;
+    assert!(get(server.local_addr(), "/").await.ends_with("\r\n\r\none"));
+
+    //swapped the way deploy tools do it, a new link renamed over the old one
+    std::os::unix::fs::symlink("v2", dir.path().join("next")).unwrap();
+    fs::rename(dir.path().join("next"), &current).unwrap();
+    let response = get(server.local_addr(), "/").await;
+    assert!(response.ends_with("\r\n\r\ntwo"), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_closes_the_listener() {
+    let root = tempfile::tempdir().unwrap();
+    let server = serve_dir(root.path()).await;
+    let addr = server.local_addr();
+
+    server.shutdown().await.unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+//a file big enough to be streamed, and to fill the socket buffers of a client that isn't reading
+fn big_file(doc_root: &Path) -> usize {
+    let len = 32 * 1024 * 1024;
+    fs::write(doc_root.join("big.bin"), vec![7u8; len]).unwrap();
+    len
+}
+
+async fn request_big(addr: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"GET /big.bin HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    //let the server get going on the response
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    stream
+}
+
+#[tokio::test]
+async fn shutdown_closes_idle_keep_alive_connections() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("index.html"), "hi").unwrap();
+    let server = serve_dir(root.path()).await;
+
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert!(String::from_utf8_lossy(&buf[..n]).contains("Connection: keep-alive"));
+    assert_eq!(server.connections(), 1);
+
+    let started = Instant::now();
+    assert_eq!(server.shutdown().await.unwrap(), Shutdown::Drained);
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn shutdown_lets_in_flight_responses_finish() {
+    let root = tempfile::tempdir().unwrap();
+    let len = big_file(root.path());
+    let server = serve_dir(root.path()).await;
+
+    let mut stream = request_big(server.local_addr()).await;
+    let shutdown = tokio::spawn(server.shutdown());
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let head = String::from_utf8_lossy(&response[..400]).into_owned();
+    assert!(head.contains(&format!("Content-Length: {}", len)), "{}", head);
+    assert!(head.contains("Connection: keep-alive"), "{}", head);
+    assert!(response.len() > len);
+
+    assert_eq!(shutdown.await.unwrap().unwrap(), Shutdown::Drained);
+}
+
+#[tokio::test]
+async fn shutdown_cuts_connections_after_the_grace_period() {
+    let root = tempfile::tempdir().unwrap();
+    big_file(root.path());
+    let mut config = Config::default();
+    config.server.doc_root = root.path().to_path_buf();
+    config.server.shutdown_grace = 1;
+    let server = serve(config).await;
+
+    let mut stream = request_big(server.local_addr()).await;
+
+    let started = Instant::now();
+    assert_eq!(server.shutdown().await.unwrap(), Shutdown::Aborted { connections: 1 });
+    assert!(started.elapsed() >= Duration::from_secs(1));
+
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response).await;
+    assert!(response.len() < 32 * 1024 * 1024);
+}
+
+#[tokio::test]
+async fn reload_applies_to_new_connections_only() {
+    let roots = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    fs::write(roots[0].path().join("index.html"), "old").unwrap();
+    fs::write(roots[1].path().join("index.html"), "new").unwrap();
+    let server = serve_dir(roots[0].path()).await;
+
+    let mut kept = TcpStream::connect(server.local_addr()).await.unwrap();
+    kept.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut buf = [0u8; 1024];
+    let n = kept.read(&mut buf).await.unwrap();
+    assert!(String::from_utf8_lossy(&buf[..n]).ends_with("\r\n\r\nold"));
+
+    let mut config = Config::default();
+    config.server.doc_root = roots[1].path().to_path_buf();
+    server.reloader().reload(config).unwrap();
+    assert_eq!(server.reloader().config().server.doc_root, roots[1].path());
+
+    assert!(get(server.local_addr(), "/").await.ends_with("\r\n\r\nnew"));
+
+    //the connection from before the reload carries on with the settings it started with
+    kept.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+    let mut response = Vec::new();
+    kept.read_to_end(&mut response).await.unwrap();
+    assert!(String::from_utf8_lossy(&response).ends_with("\r\n\r\nold"));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn invalid_reload_keeps_the_running_config() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("index.html"), "still here").unwrap();
+    let server = serve_dir(root.path()).await;
+
+    let mut config = Config::default();
+    config.server.doc_root = root.path().join("missing");
+    assert!(server.reloader().reload(config).is_err());
+
+    let broken = root.path().join("broken.toml");
+    fs::write(&broken, "[server]\nlisten = 8080\n").unwrap();
+    assert!(server.reloader().reload_file(&broken).is_err());
+
+    assert_eq!(server.reloader().config().server.doc_root, root.path());
+    assert!(get(server.local_addr(), "/").await.ends_with("\r\n\r\nstill here"));
+
+    server.shutdown().await.unwrap();
+}
+
+#[test]
+fn invalid_config_is_rejected_before_binding() {
+    let result = Server::builder(Config::default())
+        .listen("127.0.0.1:0".parse().unwrap())
+        .doc_root("/definitely/not/a/dir")
+        .build();
+    assert!(result.is_err());
+}
@@ -0,0 +1,416 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore, SupportedProtocolVersion};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use webserv::config::{ClientAuthConfig, Config, HstsConfig, RedirectConfig, SniCertConfig, TlsVersion};
+
+mod common;
+use common::{self_signed, self_signed_pair, start, tls, tls_config};
+
+fn connector(trusted: CertificateDer<'static>, versions: &[&'static SupportedProtocolVersion]) -> TlsConnector {
+    connector_with(trusted, versions, None)
+}
+
+fn connector_with(
+    trusted: CertificateDer<'static>,
+    versions: &[&'static SupportedProtocolVersion],
+    client_cert: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+) -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add(trusted).unwrap();
+    let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_root_certificates(roots);
+    let mut config = match client_cert {
+        Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn get(connector: &TlsConnector, addr: SocketAddr, path: &str) -> Result<(Option<Vec<u8>>, String), std::io::Error> {
+    get_named(connector, addr, "localhost", path).await
+}
+
+async fn get_named(connector: &TlsConnector, addr: SocketAddr, name: &str, path: &str) -> Result<(Option<Vec<u8>>, String), std::io::Error> {
+    let tcp = TcpStream::connect(addr).await?;
+    let mut stream = connector.connect(ServerName::try_from(name.to_string()).unwrap(), tcp).await?;
+    let alpn = stream.get_ref().1.alpn_protocol().map(|p| p.to_vec());
+
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok((alpn, String::from_utf8(response).unwrap()))
+}
+
+#[tokio::test]
+async fn serves_over_tls() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = self_signed(dir.path());
+    fs::write(dir.path().join("index.html"), "secure").unwrap();
+
+    let server = start(tls_config(dir.path()), dir.path()).await.unwrap();
+    assert!(server.local_addrs().is_empty());
+    let addr = server.tls_addrs()[0];
+
+    let connector = connector(cert, rustls::DEFAULT_VERSIONS);
+    let (alpn, response) = get(&connector, addr, "/").await.unwrap();
+    assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nsecure"), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn plain_and_tls_listeners_side_by_side() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = self_signed(dir.path());
+    fs::write(dir.path().join("index.html"), "both").unwrap();
+
+    let mut config = tls_config(dir.path());
+    config.server.listen = vec!["127.0.0.1:0".parse().unwrap()];
+    let server = start(config, dir.path()).await.unwrap();
+
+    let (_, response) = get(&connector(cert, rustls::DEFAULT_VERSIONS), server.tls_addrs()[0], "/").await.unwrap();
+    assert!(response.ends_with("\r\n\r\nboth"));
+
+    //plain HTTP on the TLS port doesn't get a response
+    let mut plain = TcpStream::connect(server.tls_addrs()[0]).await.unwrap();
+    plain.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut buf = Vec::new();
+    let _ = plain.read_to_end(&mut buf).await;
+    assert!(!String::from_utf8_lossy(&buf).contains("HTTP/1.1 200"));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn min_version_refuses_older_clients() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = self_signed(dir.path());
+
+    let mut config = tls_config(dir.path());
+    tls(&mut config).min_version = TlsVersion::Tls13;
+    let server = start(config, dir.path()).await.unwrap();
+    let addr = server.tls_addrs()[0];
+
+    assert!(get(&connector(cert.clone(), &[&rustls::version::TLS12]), addr, "/").await.is_err());
+    assert!(get(&connector(cert, &[&rustls::version::TLS13]), addr, "/").await.is_ok());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn unusable_key_fails_startup() {
+    let dir = tempfile::tempdir().unwrap();
+    self_signed(dir.path());
+    fs::write(dir.path().join("key.pem"), "not a key").unwrap();
+
+    assert!(start(tls_config(dir.path()), dir.path()).await.is_err());
+}
+
+#[tokio::test]
+async fn sni_picks_the_certificate() {
+    let dir = tempfile::tempdir().unwrap();
+    let default = self_signed_pair(dir.path(), "", &["localhost", "other.test"]);
+    let exact = self_signed_pair(dir.path(), "exact-", &["exact.test"]);
+    let wildcard = self_signed_pair(dir.path(), "wild-", &["*.wild.test"]);
+
+    let mut config = tls_config(dir.path());
+    let tls = tls(&mut config);
+    for (prefix, names) in [("exact-", vec!["exact.test"]), ("wild-", vec!["*.wild.test"])] {
+        tls.certificates.push(SniCertConfig {
+            names: names.iter().map(|n| n.to_string()).collect(),
+            cert: dir.path().join(format!("{}cert.pem", prefix)),
+            key: dir.path().join(format!("{}key.pem", prefix)),
+        });
+    }
+    let server = start(config, dir.path()).await.unwrap();
+    let addr = server.tls_addrs()[0];
+
+    //each client only trusts the certificate it expects, so a handshake only succeeds with the right one
+    let versions = rustls::DEFAULT_VERSIONS;
+    assert!(get_named(&connector(exact.clone(), versions), addr, "exact.test", "/").await.is_ok());
+    assert!(get_named(&connector(wildcard.clone(), versions), addr, "a.wild.test", "/").await.is_ok());
+    assert!(get_named(&connector(default.clone(), versions), addr, "other.test", "/").await.is_ok());
+    assert!(get_named(&connector(exact, versions), addr, "other.test", "/").await.is_err());
+    //a wildcard only covers a single label
+    assert!(get_named(&connector(wildcard, versions), addr, "a.b.wild.test", "/").await.is_err());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn renewed_certificate_is_picked_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let old = self_signed(dir.path());
+    let server = start(tls_config(dir.path()), dir.path()).await.unwrap();
+    let addr = server.tls_addrs()[0];
+    assert!(get(&connector(old.clone(), rustls::DEFAULT_VERSIONS), addr, "/").await.is_ok());
+
+    let new = self_signed(dir.path());
+    let renewed = connector(new, rustls::DEFAULT_VERSIONS);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while get(&renewed, addr, "/").await.is_err() {
+        assert!(Instant::now() < deadline, "renewed certificate never served");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(get(&connector(old, rustls::DEFAULT_VERSIONS), addr, "/").await.is_err());
+
+    //a broken key is refused and the renewed pair stays in use
+    fs::write(dir.path().join("key.pem"), "garbage").unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(get(&renewed, addr, "/").await.is_ok());
+
+    server.shutdown().await.unwrap();
+}
+
+//a CA written to dir/<name>.pem, handed back so client certificates can be signed with it
+fn make_ca(dir: &Path, name: &str) -> (rcgen::Certificate, KeyPair) {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, name);
+    let ca = params.self_signed(&key).unwrap();
+    fs::write(dir.join(format!("{}.pem", name)), ca.pem()).unwrap();
+    (ca, key)
+}
+
+fn client_cert(ca: &(rcgen::Certificate, KeyPair), cn: &str, san: &str) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec![san.to_string()]).unwrap();
+    params.distinguished_name.push(DnType::CommonName, cn);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let cert = params.signed_by(&key, &ca.0, &ca.1).unwrap();
+    (cert.der().clone(), PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())))
+}
+
+fn mtls_config(dir: &Path, allow: &[&str]) -> Config {
+    let mut config = tls_config(dir);
+    config.error_pages.insert(403, "403.html".into());
+    tls(&mut config).client_auth = Some(ClientAuthConfig {
+        ca: dir.join("clients.pem"),
+        paths: vec!["/private/".to_string()],
+        allow: allow.iter().map(|a| a.to_string()).collect(),
+    });
+    config
+}
+
+fn mtls_doc_root(dir: &Path) {
+    fs::create_dir(dir.join("private")).unwrap();
+    fs::create_dir(dir.join("public")).unwrap();
+    fs::write(dir.join("private/secret.html"), "secret").unwrap();
+    fs::write(dir.join("public/index.html"), "public").unwrap();
+    fs::write(dir.join("403.html"), "custom forbidden").unwrap();
+}
+
+#[tokio::test]
+async fn client_certificate_required_under_configured_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let server_cert = self_signed(dir.path());
+    let ca = make_ca(dir.path(), "clients");
+    let stranger = make_ca(dir.path(), "stranger");
+    mtls_doc_root(dir.path());
+
+    let server = start(mtls_config(dir.path(), &[]), dir.path()).await.unwrap();
+    let addr = server.tls_addrs()[0];
+    let versions = rustls::DEFAULT_VERSIONS;
+
+    let anonymous = connector(server_cert.clone(), versions);
+    let (_, response) = get(&anonymous, addr, "/public/index.html").await.unwrap();
+    assert!(response.ends_with("\r\n\r\npublic"), "{}", response);
+    //files that don't exist get the same 403 as ones that do, so which ones exist doesn't leak
+    let paths = [
+        "/private/secret.html",
+        "/public/../private/secret.html",
+        "//private/secret.html",
+        "/private/missing.html",
+        "/public/../private/missing.html",
+        "/%70rivate/missing.html",
+    ];
+    for path in paths {
+        let (_, response) = get(&anonymous, addr, path).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 "), "{} -> {}", path, response);
+        assert!(response.ends_with("\r\n\r\ncustom forbidden"), "{}", response);
+    }
+
+    let trusted = connector_with(server_cert.clone(), versions, Some(client_cert(&ca, "ops", "dash.client")));
+    let (_, response) = get(&trusted, addr, "/private/secret.html").await.unwrap();
+    assert!(response.ends_with("\r\n\r\nsecret"), "{}", response);
+    let (_, response) = get(&trusted, addr, "/private/missing.html").await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+
+    //a certificate from some other CA still gets through the handshake, and then a 403
+    let untrusted = connector_with(server_cert, versions, Some(client_cert(&stranger, "ops", "dash.client")));
+    let (_, response) = get(&untrusted, addr, "/private/secret.html").await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn client_certificate_allow_list() {
+    let dir = tempfile::tempdir().unwrap();
+    let server_cert = self_signed(dir.path());
+    let ca = make_ca(dir.path(), "clients");
+    mtls_doc_root(dir.path());
+
+    let server = start(mtls_config(dir.path(), &["dash.client", "CN=admin"]), dir.path()).await.unwrap();
+    let addr = server.tls_addrs()[0];
+    let versions = rustls::DEFAULT_VERSIONS;
+
+    let by_san = connector_with(server_cert.clone(), versions, Some(client_cert(&ca, "ops", "dash.client")));
+    assert!(get(&by_san, addr, "/private/secret.html").await.unwrap().1.ends_with("\r\n\r\nsecret"));
+
+    let by_subject = connector_with(server_cert.clone(), versions, Some(client_cert(&ca, "admin", "other.client")));
+    assert!(get(&by_subject, addr, "/private/secret.html").await.unwrap().1.ends_with("\r\n\r\nsecret"));
+
+    let neither = connector_with(server_cert, versions, Some(client_cert(&ca, "ops", "other.client")));
+    assert!(get(&neither, addr, "/private/secret.html").await.unwrap().1.starts_with("HTTP/1.1 403 "));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reload_applies_client_auth_paths_and_allow() {
+    let dir = tempfile::tempdir().unwrap();
+    let server_cert = self_signed(dir.path());
+    let ca = make_ca(dir.path(), "clients");
+    make_ca(dir.path(), "stranger");
+    mtls_doc_root(dir.path());
+
+    let server = start(mtls_config(dir.path(), &["dash.client"]), dir.path()).await.unwrap();
+    let addr = server.tls_addrs()[0];
+    let versions = rustls::DEFAULT_VERSIONS;
+    let anonymous = connector(server_cert.clone(), versions);
+    let other = connector_with(server_cert, versions, Some(client_cert(&ca, "ops", "other.client")));
+    assert!(get(&other, addr, "/private/secret.html").await.unwrap().1.starts_with("HTTP/1.1 403 "));
+    assert!(get(&anonymous, addr, "/public/index.html").await.unwrap().1.ends_with("\r\n\r\npublic"));
+
+    let mut config = server.reloader().config();
+    let client_auth = tls(&mut config).client_auth.as_mut().unwrap();
+    client_auth.paths = vec!["/public/".to_string()];
+    client_auth.allow = vec!["other.client".to_string()];
+    server.reloader().reload(config).unwrap();
+    assert!(get(&other, addr, "/private/secret.html").await.unwrap().1.ends_with("\r\n\r\nsecret"));
+    assert!(get(&anonymous, addr, "/public/index.html").await.unwrap().1.starts_with("HTTP/1.1 403 "));
+
+    //the handshake is set up at startup, so changing what it asks for is refused
+    let mut config = server.reloader().config();
+    tls(&mut config).client_auth.as_mut().unwrap().ca = dir.path().join("stranger.pem");
+    let err = server.reloader().reload(config).unwrap_err().to_string();
+    assert!(err.contains("tls.client_auth.ca"), "{}", err);
+    let mut config = server.reloader().config();
+    tls(&mut config).client_auth = None;
+    let err = server.reloader().reload(config).unwrap_err().to_string();
+    assert!(err.contains("tls.client_auth"), "{}", err);
+    assert_eq!(server.reloader().config().tls.unwrap().client_auth.unwrap().allow, ["other.client"]);
+
+    server.shutdown().await.unwrap();
+}
+
+async fn plain_request(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+#[tokio::test]
+async fn redirect_listener_points_at_https() {
+    let dir = tempfile::tempdir().unwrap();
+    self_signed(dir.path());
+    fs::create_dir_all(dir.path().join(".well-known/acme-challenge")).unwrap();
+    fs::write(dir.path().join(".well-known/acme-challenge/token"), "challenge").unwrap();
+
+    let mut config = tls_config(dir.path());
+    tls(&mut config).redirect = Some(RedirectConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        status: 301,
+        https_port: None,
+        exceptions: vec!["/.well-known/acme-challenge/".to_string()],
+    });
+    let server = start(config, dir.path()).await.unwrap();
+    let addr = server.redirect_addrs()[0];
+    let location = format!("Location: https://example.com:{}/a/b.html?x=1&y=2\r\n", server.tls_addrs()[0].port());
+
+    let response = plain_request(addr, "GET /a/b.html?x=1&y=2 HTTP/1.1\r\nHost: example.com:8080\r\nConnection: close\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 301 "), "{}", response);
+    assert!(response.contains(&location), "{}", response);
+
+    //anything but GET and HEAD keeps its method, so it gets a 308
+    let response = plain_request(addr, "POST /a/b.html?x=1&y=2 HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 308 "), "{}", response);
+    assert!(response.contains(&location), "{}", response);
+
+    let response = plain_request(addr, "GET /.well-known/acme-challenge/token HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(response.ends_with("\r\n\r\nchallenge"), "{}", response);
+
+    let response = plain_request(addr, "GET / HTTP/1.1\r\nHost: bad host\r\nConnection: close\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn redirect_refuses_targets_that_would_split_the_response() {
+    let dir = tempfile::tempdir().unwrap();
+    self_signed(dir.path());
+
+    let mut config = tls_config(dir.path());
+    tls(&mut config).redirect = Some(RedirectConfig {
+        listen: vec!["127.0.0.1:0".parse().unwrap()],
+        status: 301,
+        https_port: None,
+        exceptions: Vec::new(),
+    });
+    let server = start(config, dir.path()).await.unwrap();
+    let addr = server.redirect_addrs()[0];
+
+    let targets = ["/x\nSet-Cookie:pwned=1", "/x\rSet-Cookie:pwned=1", "/x\ty", "/caf\u{e9}", "/a b"];
+    for target in targets.iter() {
+        let request = format!("GET {} HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n", target);
+        let response = plain_request(addr, &request).await;
+        assert!(response.starts_with("HTTP/1.1 400 "), "{:?}: {}", target, response);
+        assert!(!response.contains("Location"), "{:?}: {}", target, response);
+        assert!(!response.contains("pwned"), "{:?}: {}", target, response);
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn hsts_only_on_https() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = self_signed(dir.path());
+    fs::write(dir.path().join("index.html"), "strict").unwrap();
+
+    let mut config = tls_config(dir.path());
+    config.server.listen = vec!["127.0.0.1:0".parse().unwrap()];
+    tls(&mut config).hsts = Some(HstsConfig { max_age: 63_072_000, include_subdomains: true, preload: true });
+    let server = start(config, dir.path()).await.unwrap();
+
+    let (_, response) = get(&connector(cert, rustls::DEFAULT_VERSIONS), server.tls_addrs()[0], "/").await.unwrap();
+    assert!(response.contains("\r\nStrict-Transport-Security: max-age=63072000; includeSubDomains; preload\r\n"), "{}", response);
+
+    let response = plain_request(server.local_addrs()[0], "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+    assert!(!response.contains("Strict-Transport-Security"), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
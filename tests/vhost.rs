@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use webserv::config::{Config, HostClientAuthConfig, HostConfig};
+
+mod common;
+use common::{body, get_host, request, serve};
+
+//a doc root under dir holding an index.html that says which one it is
+fn doc_root(dir: &Path, name: &str) -> PathBuf {
+    let root = dir.join(name);
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("index.html"), name).unwrap();
+    root
+}
+
+fn host(names: &[&str], doc_root: PathBuf) -> HostConfig {
+    HostConfig {
+        names: names.iter().map(|n| n.to_string()).collect(),
+        doc_root,
+        index: None,
+        error_pages: BTreeMap::new(),
+        headers: BTreeMap::new(),
+        client_auth: None,
+        default: false,
+    }
+}
+
+fn hosts_config(dir: &Path) -> Config {
+    let mut config = Config::default();
+    config.server.doc_root = doc_root(dir, "main");
+    config.hosts = vec![
+        host(&["example.com", "www.example.com"], doc_root(dir, "example")),
+        host(&["*.example.org"], doc_root(dir, "wildcard")),
+        host(&["api.example.org"], doc_root(dir, "api")),
+    ];
+    config
+}
+
+#[tokio::test]
+async fn hosts_are_picked_by_name() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = serve(hosts_config(dir.path())).await;
+    let addr = server.local_addrs()[0];
+
+    let cases = [
+        ("example.com", "example"),
+        ("WWW.Example.COM:8080", "example"),
+        ("example.com.", "example"),
+        ("blog.example.org", "wildcard"),
+        //exact names win over a wildcard that covers them too
+        ("api.example.org", "api"),
+        //a wildcard only covers a single label
+        ("a.b.example.org", "main"),
+        ("example.org", "main"),
+        ("unknown.test", "main"),
+        ("127.0.0.1", "main"),
+    ];
+    for (host, expected) in cases.iter() {
+        let response = get_host(addr, host, "/").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}: {}", host, response);
+        assert_eq!(body(&response), *expected, "{}", host);
+    }
+
+    //absolute-form names the host over the Host header
+    let response = request(addr, "GET http://blog.example.org/index.html?x=1 HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert_eq!(body(&response), "wildcard");
+    let response = request(addr, "GET http://EXAMPLE.com HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert_eq!(body(&response), "example");
+
+    //a host's doc root is all it can see
+    fs::write(dir.path().join("main").join("only-main.txt"), "main").unwrap();
+    assert!(get_host(addr, "localhost", "/only-main.txt").await.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(get_host(addr, "example.com", "/only-main.txt").await.starts_with("HTTP/1.1 404 Not found\r\n"));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn default_host_serves_unclaimed_names() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = hosts_config(dir.path());
+    config.hosts[1].default = true;
+    let server = serve(config).await;
+    let addr = server.local_addrs()[0];
+
+    assert_eq!(body(&get_host(addr, "unknown.test", "/").await), "wildcard");
+    assert_eq!(body(&request(addr, "GET / HTTP/1.0\r\n\r\n").await), "wildcard");
+    assert_eq!(body(&get_host(addr, "example.com", "/").await), "example");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn strict_hosts_refuse_missing_and_unknown_names() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = hosts_config(dir.path());
+    config.server.strict_hosts = true;
+    let server = serve(config).await;
+    let addr = server.local_addrs()[0];
+
+    let response = get_host(addr, "unknown.test", "/").await;
+    assert!(response.starts_with("HTTP/1.1 421 Misdirected request\r\n"), "{}", response);
+    let response = request(addr, "GET / HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad request\r\n"), "{}", response);
+    let response = get_host(addr, "bad host!", "/").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad request\r\n"), "{}", response);
+    assert_eq!(body(&get_host(addr, "www.example.com", "/").await), "example");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn hosts_have_their_own_index_error_pages_and_headers() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = hosts_config(dir.path());
+    let example = dir.path().join("example");
+    fs::write(example.join("home.html"), "home").unwrap();
+    fs::write(example.join("missing.html"), "example has no such page").unwrap();
+    fs::write(dir.path().join("main").join("404.html"), "main has no such page").unwrap();
+    config.hosts[0].index = Some(vec!["home.html".to_string()]);
+    config.hosts[0].error_pages.insert(404, PathBuf::from("missing.html"));
+    config.hosts[0].headers.insert("Cache-Control".to_string(), "max-age=60".to_string());
+    let server = serve(config).await;
+    let addr = server.local_addrs()[0];
+
+    let response = get_host(addr, "example.com", "/").await;
+    assert_eq!(body(&response), "home");
+    assert!(response.contains("\r\nCache-Control: max-age=60\r\n"), "{}", response);
+    let response = get_host(addr, "example.com", "/nope").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not found\r\n"), "{}", response);
+    assert_eq!(body(&response), "example has no such page");
+    assert!(response.contains("\r\nCache-Control: max-age=60\r\n"), "{}", response);
+
+    let response = get_host(addr, "localhost", "/nope").await;
+    assert_eq!(body(&response), "main has no such page");
+    assert!(!response.contains("Cache-Control"), "{}", response);
+    //the top level error pages apply to hosts that don't set their own, under their own doc root
+    let response = get_host(addr, "blog.example.org", "/nope").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not found\r\n"), "{}", response);
+    assert!(!body(&response).contains("main has no such page"), "{}", response);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reload_picks_up_new_hosts() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = serve(hosts_config(dir.path())).await;
+    let addr = server.local_addrs()[0];
+    assert_eq!(body(&get_host(addr, "new.test", "/").await), "main");
+
+    let mut config = server.reloader().config();
+    config.hosts.push(host(&["new.test"], doc_root(dir.path(), "new")));
+    server.reloader().reload(config).unwrap();
+    assert_eq!(body(&get_host(addr, "new.test", "/").await), "new");
+    assert_eq!(body(&get_host(addr, "example.com", "/").await), "example");
+
+    server.shutdown().await.unwrap();
+}
+
+type ConfigChange = fn(&mut Config);
+
+#[test]
+fn hosts_config_is_validated() {
+    let dir = tempfile::tempdir().unwrap();
+    let valid = hosts_config(dir.path());
+    assert!(valid.validate().is_ok(), "{:?}", valid.validate());
+
+    let cases: [(&str, ConfigChange); 9] = [
+        ("no names", |c| c.hosts[0].names.clear()),
+        ("uppercase name", |c| c.hosts[0].names[0] = "Example.com".to_string()),
+        ("name claimed twice", |c| c.hosts[1].names.push("example.com".to_string())),
+        ("missing doc root", |c| c.hosts[0].doc_root = PathBuf::from("/nonexistent/webserv")),
+        ("two defaults", |c| {
+            c.hosts[0].default = true;
+            c.hosts[1].default = true;
+        }),
+        ("framing header", |c| {
+            c.hosts[0].headers.insert("Content-Length".to_string(), "0".to_string());
+        }),
+        ("header injection", |c| {
+            c.hosts[0].headers.insert("X-Test".to_string(), "a\r\nSet-Cookie: b".to_string());
+        }),
+        ("client_auth without tls", |c| {
+            c.hosts[0].client_auth = Some(HostClientAuthConfig { paths: vec!["/".to_string()], allow: Vec::new() });
+        }),
+        ("strict without hosts", |c| {
+            c.server.strict_hosts = true;
+            c.hosts.clear();
+        }),
+    ];
+    for (case, change) in cases.iter() {
+        let mut config = valid.clone();
+        change(&mut config);
+        assert!(config.validate().is_err(), "{} was accepted", case);
+    }
+}